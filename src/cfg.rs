@@ -0,0 +1,368 @@
+use std::path::PathBuf;
+
+use eyre::{Result, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::error::SectionError;
+use crate::geometry::{DEFAULT_FINGER_STRENGTH, Geometry};
+use crate::model::{
+    BigramTable, DEFAULT_CHORD_COST, DEFAULT_LAYER_COST, DEFAULT_LAYER_SWITCH_COST,
+    DEFAULT_LOAD_PENALTY, DEFAULT_SHIFT_COST, DEFAULT_SHIFT_SAME_HAND, DEFAULT_THUMB_BIGRAM_COST,
+    Model, Penalty, THUMB,
+};
+use crate::preset::Preset;
+use crate::types::{Kc, MAX_LAYERS};
+
+const HAND_UNIGRAM_COST: [&str; 2] = ["left_unigram_cost", "right_unigram_cost"];
+const HAND_BIGRAM_COST: [&str; 2] = ["left_bigram_cost", "right_bigram_cost"];
+
+// Partially specified model. Sections which are not given can be filled in
+// from another config, e.g. a geometry preset.
+#[must_use]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelCfg {
+    #[serde(alias = "extends")]
+    pub include: Option<PathBuf>, // Model this one extends, relative to this file.
+    pub preset: Option<Preset>, // Fills in the sections which aren't given.
+    pub layout: Option<String>,
+    pub layers: Option<usize>, // Keys holds this many layers of the layout, one after another.
+    pub layer_cost: Option<f64>,
+    pub layer_switch_cost: Option<f64>,
+    pub layer_capacity: Option<Vec<usize>>, // Most keys each layer may hold.
+    pub combos: Option<Vec<(usize, usize)>>, // Pairs of keys, by index in the layout.
+    pub chord_cost: Option<f64>,
+    pub keys: Option<Vec<Kc>>,
+    pub fixed: Option<Vec<Kc>>,
+    pub unigram_cost: Option<Vec<f64>>,
+    pub bigram_cost: Option<Vec<f64>>,
+    pub left_unigram_cost: Option<Vec<f64>>,
+    pub right_unigram_cost: Option<Vec<f64>>,
+    pub left_bigram_cost: Option<Vec<f64>>,
+    pub right_bigram_cost: Option<Vec<f64>>,
+    pub thumb_bigram_cost: Option<Vec<f64>>,
+    pub shift_same_hand: Option<f64>,
+    pub shift_pairs: Option<bool>,
+    pub shift_cost: Option<f64>,
+    pub row: Option<Vec<i32>>,
+    pub hand: Option<Vec<i32>>,
+    pub finger: Option<Vec<i32>>,
+    pub pos_x: Option<Vec<f64>>,
+    pub pos_y: Option<Vec<f64>>,
+    pub home: Option<Vec<bool>>,
+    pub finger_strength: Option<Vec<f64>>,
+    pub finger_load: Option<Vec<(f64, f64)>>,
+    pub finger_load_penalty: Option<Penalty>,
+    pub hand_balance: Option<f64>,
+    pub hand_balance_penalty: Option<Penalty>,
+}
+
+impl ModelCfg {
+    // Sections given in |over| replace the ones in |self|.
+    pub fn merge(self, over: ModelCfg) -> ModelCfg {
+        ModelCfg {
+            include: over.include.or(self.include),
+            preset: over.preset.or(self.preset),
+            layout: over.layout.or(self.layout),
+            layers: over.layers.or(self.layers),
+            layer_cost: over.layer_cost.or(self.layer_cost),
+            layer_switch_cost: over.layer_switch_cost.or(self.layer_switch_cost),
+            layer_capacity: over.layer_capacity.or(self.layer_capacity),
+            combos: over.combos.or(self.combos),
+            chord_cost: over.chord_cost.or(self.chord_cost),
+            keys: over.keys.or(self.keys),
+            fixed: over.fixed.or(self.fixed),
+            unigram_cost: over.unigram_cost.or(self.unigram_cost),
+            bigram_cost: over.bigram_cost.or(self.bigram_cost),
+            left_unigram_cost: over.left_unigram_cost.or(self.left_unigram_cost),
+            right_unigram_cost: over.right_unigram_cost.or(self.right_unigram_cost),
+            left_bigram_cost: over.left_bigram_cost.or(self.left_bigram_cost),
+            right_bigram_cost: over.right_bigram_cost.or(self.right_bigram_cost),
+            thumb_bigram_cost: over.thumb_bigram_cost.or(self.thumb_bigram_cost),
+            shift_same_hand: over.shift_same_hand.or(self.shift_same_hand),
+            shift_pairs: over.shift_pairs.or(self.shift_pairs),
+            shift_cost: over.shift_cost.or(self.shift_cost),
+            row: over.row.or(self.row),
+            hand: over.hand.or(self.hand),
+            finger: over.finger.or(self.finger),
+            pos_x: over.pos_x.or(self.pos_x),
+            pos_y: over.pos_y.or(self.pos_y),
+            home: over.home.or(self.home),
+            finger_strength: over.finger_strength.or(self.finger_strength),
+            finger_load: over.finger_load.or(self.finger_load),
+            finger_load_penalty: over.finger_load_penalty.or(self.finger_load_penalty),
+            hand_balance: over.hand_balance.or(self.hand_balance),
+            hand_balance_penalty: over.hand_balance_penalty.or(self.hand_balance_penalty),
+        }
+    }
+
+    pub fn build(mut self) -> Result<Model> {
+        if let Some(include) = &self.include {
+            return Err(eyre!("include of {} must be loaded from a file", include.display()));
+        }
+        if let Some(preset) = self.preset.take() {
+            return preset.cfg().merge(self).build();
+        }
+        let layout = self.layout.ok_or_else(|| eyre!("missing layout"))?;
+        let mut universe = self.keys.ok_or_else(|| eyre!("missing keys"))?;
+        let row = self.row.ok_or_else(|| eyre!("missing row"))?;
+        let hand = self.hand.ok_or_else(|| eyre!("missing hand"))?;
+        let finger = self.finger.ok_or_else(|| eyre!("missing finger"))?;
+        let layers = self.layers.unwrap_or(1);
+        if !(1..=MAX_LAYERS).contains(&layers) {
+            let msg = format!("layers must be between 1 and {MAX_LAYERS}");
+            return Err(SectionError::report("layers", msg));
+        }
+        let layer_capacity = self.layer_capacity.unwrap_or_default();
+        if !layer_capacity.is_empty() && layer_capacity.len() != layers {
+            let msg = format!(
+                "layer_capacity has {} values but there are {layers} layers",
+                layer_capacity.len()
+            );
+            return Err(SectionError::report("layer_capacity", msg));
+        }
+        // Given layer_capacity, keys may list fewer keys than there are places for
+        // and the optimiser then chooses their layers too. Fixed keys keep their
+        // places, the rest fill the other places in order while their layer has
+        // room, and any left are empty.
+        let combos = self.combos.unwrap_or_default();
+        let places = layers * row.len() + combos.len();
+        if !layer_capacity.is_empty() && universe.len() < places {
+            let fixed = self.fixed.get_or_insert_with(|| vec![Kc::None; places]);
+            if fixed.len() != places {
+                let msg = format!("fixed has {} values but the layers hold {places}", fixed.len());
+                return Err(SectionError::report("fixed", msg));
+            }
+            // Combos are typed on the first layer.
+            let layer = |i: usize| if i < layers * row.len() { i / row.len() } else { 0 };
+            let mut used = vec![0; layers];
+            for (i, _) in fixed.iter().enumerate().filter(|&(_, &kc)| kc != Kc::None) {
+                used[layer(i)] += 1;
+            }
+            let mut free = universe.iter().filter(|kc| !fixed.contains(kc)).copied().peekable();
+            let mut filled = fixed.clone();
+            for (i, kc) in filled.iter_mut().enumerate() {
+                if *kc == Kc::None
+                    && used[layer(i)] < layer_capacity[layer(i)]
+                    && let Some(next) = free.next()
+                {
+                    *kc = next;
+                    used[layer(i)] += 1;
+                }
+            }
+            if free.peek().is_some() {
+                let msg = "too many keys for the free places within layer_capacity".to_owned();
+                return Err(SectionError::report("keys", msg));
+            }
+            universe = filled;
+        }
+        let fixed = self.fixed.unwrap_or_else(|| vec![Kc::None; universe.len()]);
+        // Combo keys come after the layers.
+        let layer_keys = universe.len().saturating_sub(combos.len());
+        if layer_keys % layers != 0 {
+            let msg = format!("{layer_keys} keys can't be split into {layers} layers");
+            return Err(SectionError::report("keys", msg));
+        }
+        if fixed.len() != universe.len() {
+            let msg =
+                format!("fixed has {} values but there are {} keys", fixed.len(), universe.len());
+            return Err(SectionError::report("fixed", msg));
+        }
+        // Every per key section needs a value for each physical key, shared by all layers.
+        let positions = layer_keys / layers;
+        let per_key = [("row", row.len()), ("hand", hand.len()), ("finger", finger.len())];
+        for (name, len) in per_key {
+            if len != positions {
+                let msg =
+                    format!("{name} has {len} values but there are {positions} keys per layer");
+                return Err(SectionError::report(name, msg));
+            }
+        }
+        let mut unigram_cost = self.unigram_cost;
+
+        let mut geometry = None;
+        if self.pos_x.is_some() || self.pos_y.is_some() {
+            let pos_x = self.pos_x.unwrap_or_default();
+            let pos_y = self.pos_y.unwrap_or_default();
+            if pos_x.len() != pos_y.len() {
+                let msg = "pos_x and pos_y must be the same size".to_owned();
+                return Err(SectionError::report("pos_y", msg));
+            }
+            let home = self.home.unwrap_or_default();
+            let strength = self.finger_strength.unwrap_or_else(|| DEFAULT_FINGER_STRENGTH.to_vec());
+            let pos = pos_x.into_iter().zip(pos_y).collect();
+            let g = Geometry::new(pos, &home, &hand, &finger, strength)?;
+            // Derive the effort grid from the geometry unless it was given explicitly.
+            if unigram_cost.is_none() {
+                unigram_cost =
+                    Some(finger.iter().enumerate().map(|(i, &f)| g.unigram_cost(i, f)).collect());
+            }
+            // An explicit bigram cost table takes precedence over the geometry.
+            if self.bigram_cost.is_none()
+                && (self.left_bigram_cost.is_none() || self.right_bigram_cost.is_none())
+            {
+                geometry = Some(g);
+            }
+        }
+        // Per hand unigram costs override the shared ones for that hand's keys.
+        let hand_unigram_cost = [self.left_unigram_cost, self.right_unigram_cost];
+        if unigram_cost.is_none() && hand_unigram_cost.iter().any(Option::is_none) {
+            return Err(eyre!("missing unigram_cost"));
+        }
+        let mut unigram_cost = unigram_cost.unwrap_or_else(|| vec![0.0; hand.len()]);
+        if unigram_cost.len() != hand.len() {
+            let msg = format!(
+                "unigram_cost has {} values but there are {} keys",
+                unigram_cost.len(),
+                hand.len()
+            );
+            return Err(SectionError::report("unigram_cost", msg));
+        }
+        for (h, costs) in hand_unigram_cost.into_iter().enumerate() {
+            let Some(costs) = costs else { continue };
+            let keys: Vec<_> = (0..hand.len()).filter(|&i| hand[i] == h as i32).collect();
+            if keys.len() != costs.len() {
+                let msg = format!(
+                    "{} has {} values but there are {} keys on that hand",
+                    HAND_UNIGRAM_COST[h],
+                    costs.len(),
+                    keys.len()
+                );
+                return Err(SectionError::report(HAND_UNIGRAM_COST[h], msg));
+            }
+            for (i, cost) in keys.into_iter().zip(costs) {
+                unigram_cost[i] = cost;
+            }
+        }
+
+        // Thumbs have their own costs, so bigram tables only cover the other fingers.
+        let fingers = finger.iter().filter(|&&f| f != THUMB).max().map_or(0, |&f| f + 1);
+        let rows: Vec<_> =
+            row.iter().zip(&finger).filter(|&(_, &f)| f != THUMB).map(|(&r, _)| r).collect();
+        let max_jump = rows.iter().max().zip(rows.iter().min()).map_or(0, |(a, b)| a - b);
+        let table = |costs: Vec<f64>, name: &'static str| -> Result<BigramTable> {
+            let table = BigramTable::new(fingers as usize, costs)
+                .map_err(|e| SectionError::report(name, format!("bad {name}: {e}")))?;
+            if table.max_jump < max_jump as usize {
+                let msg = format!(
+                    "{name} covers row jumps up to {} but the layout has jumps of {max_jump}",
+                    table.max_jump
+                );
+                return Err(SectionError::report(name, msg));
+            }
+            Ok(table)
+        };
+        let hand_bigram_cost = [
+            self.left_bigram_cost.map(|c| table(c, HAND_BIGRAM_COST[0])).transpose()?,
+            self.right_bigram_cost.map(|c| table(c, HAND_BIGRAM_COST[1])).transpose()?,
+        ];
+        let bigram_cost = match self.bigram_cost {
+            Some(costs) => table(costs, "bigram_cost")?,
+            None if geometry.is_some() || hand_bigram_cost.iter().all(Option::is_some) => {
+                BigramTable::default()
+            }
+            None => return Err(eyre!("missing bigram_cost")),
+        };
+
+        let thumb_bigram_cost = match self.thumb_bigram_cost {
+            Some(costs) => costs.try_into().map_err(|_| {
+                let msg = "thumb_bigram_cost must have exactly 3 values".to_owned();
+                SectionError::report("thumb_bigram_cost", msg)
+            })?,
+            None => DEFAULT_THUMB_BIGRAM_COST,
+        };
+
+        let model = Model {
+            layout,
+            layers,
+            layer_cost: self.layer_cost.unwrap_or(DEFAULT_LAYER_COST),
+            layer_switch_cost: self.layer_switch_cost.unwrap_or(DEFAULT_LAYER_SWITCH_COST),
+            layer_capacity,
+            universe,
+            combos,
+            chord_cost: self.chord_cost.unwrap_or(DEFAULT_CHORD_COST),
+            fixed,
+            unigram_cost,
+            bigram_cost,
+            hand_bigram_cost,
+            thumb_bigram_cost,
+            shift_same_hand: self.shift_same_hand.unwrap_or(DEFAULT_SHIFT_SAME_HAND),
+            shift_pairs: self.shift_pairs.unwrap_or(false),
+            shift_cost: self.shift_cost.unwrap_or(DEFAULT_SHIFT_COST),
+            row,
+            hand,
+            finger,
+            geometry,
+            finger_load: self.finger_load.unwrap_or_default(),
+            finger_load_penalty: self.finger_load_penalty.unwrap_or(DEFAULT_LOAD_PENALTY),
+            hand_balance: self.hand_balance,
+            hand_balance_penalty: self.hand_balance_penalty.unwrap_or(DEFAULT_LOAD_PENALTY),
+        };
+        model.validate()?;
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn hand_tables() {
+        let model = ModelCfg {
+            layout: Some("X X X X\n".to_owned()),
+            keys: Some(vec![Kc::A, Kc::B, Kc::C, Kc::D]),
+            unigram_cost: Some(vec![1.0, 2.0, 3.0, 4.0]),
+            right_unigram_cost: Some(vec![5.0, 6.0]),
+            bigram_cost: Some(vec![0.0, 1.0, 2.0, 3.0]),
+            right_bigram_cost: Some(vec![4.0, 5.0, 6.0, 7.0]),
+            row: Some(vec![0, 0, 0, 0]),
+            hand: Some(vec![0, 0, 1, 1]),
+            finger: Some(vec![1, 0, 0, 1]),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        assert_eq!(model.unigram_cost, vec![1.0, 2.0, 5.0, 6.0]);
+        // The left hand uses the shared table, the right hand its own.
+        let l = &[Kc::A, Kc::B, Kc::C, Kc::D];
+        assert_relative_eq!(2.0, model.bigram_cost(l, &[((Kc::A, Kc::B), 1.0)]));
+        assert_relative_eq!(6.0, model.bigram_cost(l, &[((Kc::D, Kc::C), 1.0)]));
+    }
+
+    #[test]
+    fn free_layers() {
+        let mut fixed = vec![Kc::None; 6];
+        fixed[1] = Kc::Mo1;
+        let cfg = ModelCfg {
+            layout: Some("X X X\n".to_owned()),
+            layers: Some(2),
+            layer_capacity: Some(vec![3, 2]),
+            keys: Some(vec![Kc::A, Kc::B, Kc::C]),
+            fixed: Some(fixed),
+            unigram_cost: Some(vec![1.0, 2.0, 3.0]),
+            bigram_cost: Some(vec![0.0, 1.0, 2.0, 3.0]),
+            row: Some(vec![0, 0, 0]),
+            hand: Some(vec![0, 0, 1]),
+            finger: Some(vec![1, 0, 0]),
+            ..Default::default()
+        };
+        let model = cfg.clone().build().unwrap();
+        // Fixed keys keep their places and the rest fill the others in order.
+        assert_eq!(model.universe, [Kc::A, Kc::Mo1, Kc::B, Kc::C, Kc::None, Kc::None]);
+        assert_relative_eq!(0.0, model.layer_capacity_cost(&model.universe));
+        // Keys only fill a layer up to its capacity.
+        let small = ModelCfg { layer_capacity: Some(vec![2, 2]), ..cfg.clone() };
+        let model = small.build().unwrap();
+        assert_eq!(model.universe, [Kc::A, Kc::Mo1, Kc::None, Kc::B, Kc::C, Kc::None]);
+        assert_relative_eq!(0.0, model.layer_capacity_cost(&model.universe));
+        // Free layers need layer_capacity, so a missing key is still an error without it.
+        let unlimited = ModelCfg { layer_capacity: None, ..cfg.clone() };
+        assert!(unlimited.build().is_err());
+        let keys = Some(vec![Kc::A, Kc::B, Kc::C, Kc::D, Kc::E, Kc::F]);
+        assert!(ModelCfg { keys, ..cfg }.build().is_err());
+    }
+}
@@ -0,0 +1,561 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use ahash::AHashMap;
+use eyre::{Result, eyre};
+use log::warn;
+use rayon::prelude::*;
+use strum::{Display as StrumDisplay, EnumString};
+use walkdir::WalkDir;
+
+use crate::error::ParseError;
+use crate::eval::Histograms;
+use crate::ingest::{HIST_VERSION, load_histograms_bin, write_histograms_bin};
+use crate::types::Kc;
+
+// File extensions read when walking a directory.
+const EXTENSIONS: &str = "asm,c,cc,cmake,conf,cpp,h,hpp,java,md,py,rb,rs,sh,tex,toml";
+
+// Version of the counting, part of the cache key. Bump it whenever counting changes so
+// cached histograms are recomputed.
+const CACHE_VERSION: u32 = 2;
+
+// Stands in for skipped text. Never counted, so it breaks up n-grams.
+const SKIPPED: char = '\0';
+
+// Characters typed with shift on a standard US host, other than capital letters.
+const SHIFTED: &str = "~!@#$%^&*()_+{}|:\"<>?";
+
+// Shifted punctuation always counted as its unshifted key, even without --shift.
+const SHIFTED_PUNCTUATION: &str = ":<>?";
+
+// Built in sets of characters to count, one per layer.
+#[must_use]
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, EnumString, StrumDisplay)]
+#[strum(serialize_all = "snake_case")]
+pub enum Charset {
+    Layer0,
+    Layer1,
+    All, // Both layers, for models with several layers.
+}
+
+impl Charset {
+    #[must_use]
+    pub fn chars(self) -> &'static str {
+        match self {
+            Charset::Layer0 => "abcdefghijklmnopqrstuvwxyz;,./",
+            Charset::Layer1 => "|*{}\"+_789#!()'=-456@&[]$\\0123",
+            Charset::All => "abcdefghijklmnopqrstuvwxyz;,./|*{}\"+_789#!()'=-456@&[]$\\0123",
+        }
+    }
+}
+
+#[must_use]
+#[derive(Debug, Clone, clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct CorpusCfg {
+    #[clap(
+        long,
+        global = true,
+        default_value = "layer0",
+        help = "Built in set of characters to count"
+    )]
+    pub charset: Charset,
+
+    #[clap(
+        long,
+        global = true,
+        value_name = "CHARS",
+        help = "Characters to count, overrides --charset"
+    )]
+    pub allowed: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        value_delimiter = ',',
+        default_value = EXTENSIONS,
+        help = "File extensions to read when walking directories, empty for all"
+    )]
+    pub ext: Vec<String>,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Count shift as its own key before shifted characters, instead of ignoring it"
+    )]
+    pub shift: bool,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Split identifiers at camelCase and snake_case boundaries, so n-grams don't span \
+                their words"
+    )]
+    pub split_identifiers: bool,
+
+    #[clap(long, global = true, help = "Skip string literals in source files")]
+    pub skip_strings: bool,
+
+    #[clap(long, global = true, help = "Skip comments in source files")]
+    pub skip_comments: bool,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Skip whitespace at the start of lines, which editors usually insert"
+    )]
+    pub strip_indent: bool,
+
+    #[clap(
+        long,
+        global = true,
+        value_delimiter = ',',
+        value_name = "EXT=WEIGHT",
+        help = "Weight of n-grams from files with each extension, defaults to 1"
+    )]
+    pub ext_weight: Vec<ExtWeight>,
+}
+
+#[must_use]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtWeight {
+    pub ext: String,
+    pub weight: f64,
+}
+
+impl FromStr for ExtWeight {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (ext, weight) =
+            s.split_once('=').ok_or_else(|| eyre!("expected EXT=WEIGHT, got {}", s))?;
+        Ok(Self { ext: ext.to_owned(), weight: weight.parse()? })
+    }
+}
+
+// Comment and string syntax of a type of source file.
+struct Syntax {
+    line_comment: Option<&'static str>,
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+}
+
+impl Syntax {
+    fn from_ext(ext: &str) -> Option<Self> {
+        let (line_comment, block_comment, quotes): (_, _, &[char]) = match ext {
+            "c" | "cc" | "cpp" | "h" | "hpp" | "java" | "rs" => {
+                (Some("//"), Some(("/*", "*/")), &['"'])
+            }
+            "cmake" | "conf" | "py" | "rb" | "sh" | "toml" => (Some("#"), None, &['"', '\'']),
+            "asm" => (Some(";"), None, &['"']),
+            "tex" => (Some("%"), None, &[]),
+            _ => return None,
+        };
+        Some(Self { line_comment, block_comment, quotes })
+    }
+
+    // Replaces skipped comments and string literals with |SKIPPED|. Both are
+    // always recognised, so e.g. a quote inside a comment doesn't start a string.
+    fn strip(&self, text: &str, strings: bool, comments: bool) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let (len, skip) = if let Some(len) = self.comment_len(rest) {
+                (len, comments)
+            } else if let Some(len) = self.string_len(rest) {
+                (len, strings)
+            } else {
+                (c.len_utf8(), false)
+            };
+            if skip {
+                out.push(SKIPPED);
+            } else {
+                out.push_str(&rest[..len]);
+            }
+            rest = &rest[len..];
+        }
+        out
+    }
+
+    // Length of the comment at the start of |s|, not including a trailing newline.
+    fn comment_len(&self, s: &str) -> Option<usize> {
+        if let Some(open) = self.line_comment.filter(|open| s.starts_with(open)) {
+            return Some(s.find('\n').unwrap_or(s.len()).max(open.len()));
+        }
+        let (open, close) = self.block_comment.filter(|(open, _)| s.starts_with(open))?;
+        Some(s[open.len()..].find(close).map_or(s.len(), |i| open.len() + i + close.len()))
+    }
+
+    // Length of the string literal at the start of |s|. Unterminated quotes, e.g.
+    // apostrophes, aren't strings.
+    fn string_len(&self, s: &str) -> Option<usize> {
+        let quote = s.chars().next().filter(|c| self.quotes.contains(c))?;
+        let mut escaped = false;
+        for (i, c) in s.char_indices().skip(1) {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '\n' => return None,
+                c if c == quote => return Some(i + 1),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl Default for CorpusCfg {
+    fn default() -> Self {
+        Self {
+            charset: Charset::Layer0,
+            allowed: None,
+            ext: EXTENSIONS.split(',').map(str::to_owned).collect(),
+            shift: false,
+            split_identifiers: false,
+            skip_strings: false,
+            skip_comments: false,
+            strip_indent: false,
+            ext_weight: vec![],
+        }
+    }
+}
+
+impl CorpusCfg {
+    // Maps each character to count to its keycode, and whether it needs shift. With
+    // --shift, shifted characters count as their unshifted key if that is allowed.
+    // Otherwise only :<>? do, and other shifted characters not in the set are dropped.
+    fn allowed(&self) -> Result<AHashMap<char, (Kc, bool)>> {
+        let chars = self.allowed.as_deref().unwrap_or_else(|| self.charset.chars());
+        let mut allowed = AHashMap::new();
+        for c in chars.chars() {
+            let kc = match c {
+                ' ' => Kc::Space,
+                '\n' => Kc::Enter,
+                c => Kc::from_str(&c.to_string())
+                    .map_err(|_| eyre!("no keycode for allowed character {:?}", c))?,
+            };
+            allowed.insert(c, (kc, false));
+        }
+        let unshifted: AHashMap<_, _> = allowed.values().map(|&(kc, _)| (kc, ())).collect();
+        let shifted = if self.shift { SHIFTED } else { SHIFTED_PUNCTUATION };
+        for c in shifted.chars() {
+            let kc = Kc::from_str(&c.to_string())?;
+            if let Some(base) = kc.unshifted().filter(|base| unshifted.contains_key(base)) {
+                allowed.entry(c).or_insert((base, true));
+            }
+        }
+        Ok(allowed)
+    }
+
+    // Hash of everything the counts depend on: how they're counted and stored, the
+    // options and the contents of each file.
+    fn hash(&self, files: &[PathBuf]) -> blake3::Hash {
+        let file_hashes: Vec<_> =
+            files.par_iter().map(|f| fs::read(f).ok().map(|data| blake3::hash(&data))).collect();
+        let mut hasher = blake3::Hasher::new();
+        // Variable length values are prefixed with their length, so they can't run together.
+        let put = |hasher: &mut blake3::Hasher, v: &[u8]| {
+            hasher.update(&(v.len() as u64).to_le_bytes());
+            hasher.update(v);
+        };
+        hasher.update(&CACHE_VERSION.to_le_bytes());
+        hasher.update(&HIST_VERSION.to_le_bytes());
+        // Destructured so adding an option forces a decision about hashing it.
+        let CorpusCfg {
+            charset,
+            allowed,
+            ext,
+            shift,
+            split_identifiers,
+            skip_strings,
+            skip_comments,
+            strip_indent,
+            ext_weight,
+        } = self;
+        put(&mut hasher, charset.to_string().as_bytes());
+        match allowed {
+            Some(chars) => put(&mut hasher, chars.as_bytes()),
+            None => put(&mut hasher, &[]),
+        }
+        hasher.update(&[u8::from(allowed.is_some())]);
+        hasher.update(&(ext.len() as u64).to_le_bytes());
+        for e in ext {
+            put(&mut hasher, e.as_bytes());
+        }
+        for &flag in [shift, split_identifiers, skip_strings, skip_comments, strip_indent] {
+            hasher.update(&[u8::from(flag)]);
+        }
+        hasher.update(&(ext_weight.len() as u64).to_le_bytes());
+        for w in ext_weight {
+            put(&mut hasher, w.ext.as_bytes());
+            hasher.update(&w.weight.to_le_bytes());
+        }
+        for (f, h) in files.iter().zip(file_hashes) {
+            // The extension decides how a file is processed.
+            put(&mut hasher, f.extension().unwrap_or_default().as_encoded_bytes());
+            // Unreadable files are skipped when counting, so just mark their position.
+            hasher.update(&h.map_or([0; 32], |h| *h.as_bytes()));
+        }
+        hasher.finalize()
+    }
+
+    fn wanted(&self, p: &Path) -> bool {
+        self.ext.is_empty() || self.ext.iter().any(|e| e == ext(p))
+    }
+
+    fn weight(&self, p: &Path) -> f64 {
+        self.ext_weight.iter().rev().find(|w| w.ext == ext(p)).map_or(1.0, |w| w.weight)
+    }
+
+    // Expands directories into the files under them.
+    pub fn files(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for p in paths {
+            if !p.is_dir() {
+                files.push(p.clone());
+                continue;
+            }
+            for entry in WalkDir::new(p).sort_by_file_name() {
+                let entry = entry?;
+                if entry.file_type().is_file() && self.wanted(entry.path()) {
+                    files.push(entry.into_path());
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn ext(p: &Path) -> &str {
+    p.extension().and_then(|e| e.to_str()).unwrap_or_default()
+}
+
+// Computes histograms for the text in |paths|. They are cached in |cache_dir| by the hash
+// of the files, so they are only recomputed when the corpus changes.
+pub fn load_corpus(paths: &[PathBuf], cfg: &CorpusCfg, cache_dir: &Path) -> Result<Histograms> {
+    let key = cfg.hash(&cfg.files(paths)?).to_hex();
+    let cached = cache_dir.join(format!("{key}.hist"));
+    if cached.is_file() {
+        return load_histograms_bin(&cached);
+    }
+    let hist = Counts::from_paths(paths, cfg)?.histograms();
+    fs::create_dir_all(cache_dir)?;
+    write_histograms_bin(&hist, &cached)?;
+    Ok(hist)
+}
+
+// Raw n-gram counts.
+#[must_use]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Counts {
+    pub unigrams: AHashMap<Kc, f64>,
+    pub bigrams: AHashMap<(Kc, Kc), f64>,
+    pub trigrams: AHashMap<(Kc, Kc, Kc), f64>,
+}
+
+impl Counts {
+    pub fn from_paths(paths: &[PathBuf], cfg: &CorpusCfg) -> Result<Self> {
+        let allowed = cfg.allowed()?;
+        let files = cfg.files(paths)?;
+        Ok(files
+            .par_iter()
+            .filter_map(|f| match fs::read_to_string(f) {
+                Ok(mut text) => {
+                    if (cfg.skip_strings || cfg.skip_comments)
+                        && let Some(syntax) = Syntax::from_ext(ext(f))
+                    {
+                        text = syntax.strip(&text, cfg.skip_strings, cfg.skip_comments);
+                    }
+                    Some(Self::from_text(&text, &allowed, cfg).scale(cfg.weight(f)))
+                }
+                Err(e) => {
+                    warn!("skipping {}: {}", f.display(), e);
+                    None
+                }
+            })
+            .reduce(Self::default, Self::merge))
+    }
+
+    // Counts key presses from a log with one key event per line, given by its evdev name (e.g.
+    // KEY_A) and optionally followed by the event value. Releases and repeats are ignored.
+    pub fn from_keylog(path: &Path, text: &str) -> Result<Self> {
+        let mut counts = Self::default();
+        let mut last = [None, None];
+        let mut unknown = 0;
+        for (i, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            let Some(name) = tokens.next() else { continue };
+            match tokens.next() {
+                None | Some("1") => {}
+                Some("0" | "2") => continue,
+                Some(v) => {
+                    let msg = format!("unknown key event value {v}");
+                    return Err(ParseError::at_token(path, i, line, v, msg).into());
+                }
+            }
+            if let Some(kc) = Kc::from_evdev(name) {
+                counts.add(kc, &mut last);
+            } else {
+                // Keys we can't place break up n-grams, like uncounted characters.
+                unknown += 1;
+                last = [None, None];
+            }
+        }
+        if unknown > 0 {
+            warn!("skipped {unknown} events for unknown keys in {}", path.display());
+        }
+        Ok(counts)
+    }
+
+    fn from_text(text: &str, allowed: &AHashMap<char, (Kc, bool)>, cfg: &CorpusCfg) -> Self {
+        let mut counts = Self::default();
+        let mut last = [None, None];
+        let (mut prev, mut line_start) = ('\n', true);
+        for c in text.chars() {
+            line_start = c == '\n' || (line_start && (c == ' ' || c == '\t'));
+            if cfg.strip_indent && line_start && c != '\n' {
+                continue;
+            }
+            let upper = c.is_uppercase();
+            let boundary = upper && (prev.is_lowercase() || prev.is_ascii_digit());
+            if cfg.split_identifiers && (boundary || c == '_' || prev == '_') {
+                last = [None, None];
+            }
+            prev = c;
+            let c = if upper { c.to_lowercase().next().unwrap_or(c) } else { c };
+            match allowed.get(&c) {
+                Some(&(kc, shifted)) => {
+                    if cfg.shift && (upper || shifted) {
+                        counts.add(Kc::Shift, &mut last);
+                    }
+                    counts.add(kc, &mut last);
+                }
+                // Characters which aren't counted break up bigrams and trigrams.
+                None => last = [None, None],
+            }
+        }
+        counts
+    }
+
+    // Counts |kc| following the |last| two keys.
+    fn add(&mut self, kc: Kc, last: &mut [Option<Kc>; 2]) {
+        *self.unigrams.entry(kc).or_default() += 1.0;
+        if let Some(p) = last[0] {
+            *self.bigrams.entry((p, kc)).or_default() += 1.0;
+            if let Some(pp) = last[1] {
+                *self.trigrams.entry((pp, p, kc)).or_default() += 1.0;
+            }
+        }
+        *last = [Some(kc), last[0]];
+    }
+
+    fn scale(mut self, weight: f64) -> Self {
+        self.unigrams.values_mut().for_each(|v| *v *= weight);
+        self.bigrams.values_mut().for_each(|v| *v *= weight);
+        self.trigrams.values_mut().for_each(|v| *v *= weight);
+        self
+    }
+
+    pub fn merge(mut self, other: Self) -> Self {
+        for (k, v) in other.unigrams {
+            *self.unigrams.entry(k).or_default() += v;
+        }
+        for (k, v) in other.bigrams {
+            *self.bigrams.entry(k).or_default() += v;
+        }
+        for (k, v) in other.trigrams {
+            *self.trigrams.entry(k).or_default() += v;
+        }
+        self
+    }
+
+    // Total (weighted) number of unigrams, bigrams and trigrams.
+    #[must_use]
+    pub fn totals(&self) -> [f64; 3] {
+        [self.unigrams.values().sum(), self.bigrams.values().sum(), self.trigrams.values().sum()]
+    }
+
+    pub fn histograms(&self) -> Histograms {
+        let [uni, bi, tri] = self.totals();
+        Histograms {
+            unigrams: self.unigrams.iter().map(|(&k, &v)| (k, v / uni)).collect(),
+            bigrams: self.bigrams.iter().map(|(&k, &v)| (k, v / bi)).collect(),
+            trigrams: self.trigrams.iter().map(|(&k, &v)| (k, v / tri)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn count(text: &str, cfg: &CorpusCfg) -> Counts {
+        Counts::from_text(text, &cfg.allowed().unwrap(), cfg)
+    }
+
+    #[test]
+    fn counts() {
+        let counts = count("Ab: a\nb", &CorpusCfg::default());
+        assert_relative_eq!(counts.unigrams[&Kc::A], 2.0);
+        assert_relative_eq!(counts.unigrams[&Kc::Semicolon], 1.0);
+        assert_relative_eq!(counts.bigrams[&(Kc::A, Kc::B)], 1.0);
+        assert_relative_eq!(counts.trigrams[&(Kc::A, Kc::B, Kc::Semicolon)], 1.0);
+        // Spaces and newlines break up n-grams.
+        assert_relative_eq!(counts.totals()[0], 5.0);
+        assert_eq!(counts.bigrams.len() + counts.trigrams.len(), 3);
+        assert_relative_eq!(counts.clone().merge(counts).unigrams[&Kc::B], 4.0);
+    }
+
+    #[test]
+    fn shift() {
+        let counts = count("aB:", &CorpusCfg { shift: true, ..Default::default() });
+        assert_relative_eq!(counts.unigrams[&Kc::Shift], 2.0);
+        assert_relative_eq!(counts.bigrams[&(Kc::A, Kc::Shift)], 1.0);
+        assert_relative_eq!(counts.bigrams[&(Kc::Shift, Kc::B)], 1.0);
+        assert_relative_eq!(counts.trigrams[&(Kc::B, Kc::Shift, Kc::Semicolon)], 1.0);
+
+        // Without --shift only :<>? count as their unshifted key.
+        let layer1 = CorpusCfg { charset: Charset::Layer1, ..Default::default() };
+        let counts = count("5%6^", &layer1);
+        assert_relative_eq!(counts.unigrams[&Kc::Num5], 1.0);
+        assert_relative_eq!(counts.unigrams[&Kc::Num6], 1.0);
+        assert_relative_eq!(counts.totals()[0], 2.0);
+        let counts = count("5%", &CorpusCfg { shift: true, ..layer1 });
+        assert_relative_eq!(counts.unigrams[&Kc::Num5], 2.0);
+    }
+
+    #[test]
+    fn keylog() {
+        let log = "KEY_LEFTSHIFT 1\nKEY_A 1\nKEY_A 0\nKEY_LEFTSHIFT 0\nKEY_BACKSPACE\nKEY_FOO\nKEY_UP\nKEY_F\n";
+        let path = Path::new("keys.log");
+        let counts = Counts::from_keylog(path, log).unwrap();
+        assert_relative_eq!(counts.totals()[0], 5.0);
+        assert_relative_eq!(counts.trigrams[&(Kc::Shift, Kc::A, Kc::Backspace)], 1.0);
+        assert!(!counts.bigrams.contains_key(&(Kc::Backspace, Kc::Up)));
+        let err = Counts::from_keylog(path, "KEY_A 1\nKEY_A 5").unwrap_err();
+        assert!(err.to_string().starts_with("keys.log:2:7: unknown key event value 5"));
+    }
+
+    #[test]
+    fn code() {
+        let cfg = CorpusCfg {
+            allowed: Some("abcdefghijklmnopqrstuvwxyz_ \n".to_owned()),
+            split_identifiers: true,
+            strip_indent: true,
+            ..Default::default()
+        };
+        let counts = count("ab_c dE\n  f", &cfg);
+        assert!(!counts.bigrams.contains_key(&(Kc::B, Kc::Underscore)));
+        assert!(!counts.bigrams.contains_key(&(Kc::D, Kc::E)));
+        assert_relative_eq!(counts.bigrams[&(Kc::Enter, Kc::F)], 1.0);
+
+        let syntax = Syntax::from_ext("rs").unwrap();
+        let text = "a(\"x//\") // it's\n/* b */c";
+        assert_eq!(syntax.strip(text, true, true), "a(\0) \0\n\0c");
+        assert_eq!(syntax.strip(text, false, true), "a(\"x//\") \0\n\0c");
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// Error in a config, seed or histogram file, pointing at where it happened.
+#[must_use]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub path: PathBuf,
+    pub line: usize, // 1-based.
+    pub col: usize,  // 1-based, in characters.
+    pub snippet: String,
+    pub msg: String,
+}
+
+impl ParseError {
+    // |token| must be a slice of |line|, and is used to work out the column.
+    pub fn at_token(path: &Path, line_idx: usize, line: &str, token: &str, msg: String) -> Self {
+        let offset = (token.as_ptr() as usize).saturating_sub(line.as_ptr() as usize);
+        Self::at_offset(path, line_idx, line, offset.min(line.len()), msg)
+    }
+
+    // |offset| is a byte offset into |line|, which is the |line_idx|th (0-based) line.
+    pub fn at_offset(path: &Path, line_idx: usize, line: &str, offset: usize, msg: String) -> Self {
+        let col = line.get(..offset).map_or(0, |s| s.chars().count()) + 1;
+        Self { path: path.to_owned(), line: line_idx + 1, col, snippet: line.to_owned(), msg }
+    }
+
+    // |offset| is a byte offset into the whole of |text|.
+    pub fn in_text(path: &Path, text: &str, offset: usize, msg: String) -> Self {
+        let offset = offset.min(text.len());
+        let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_idx = text[..line_start].matches('\n').count();
+        let line = text[line_start..].lines().next().unwrap_or_default();
+        Self::at_offset(path, line_idx, line, offset - line_start, msg)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:{}:{}: {}", self.path.display(), self.line, self.col, self.msg)?;
+        writeln!(f, "    {}", self.snippet)?;
+        write!(f, "    {}^", " ".repeat(self.col - 1))
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Error in one section of a model, found after parsing. Loading a model file
+// turns it into a ParseError at the section, if the file gives it.
+#[must_use]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionError {
+    pub section: &'static str,
+    pub msg: String,
+}
+
+impl SectionError {
+    pub fn report(section: &'static str, msg: String) -> eyre::Report {
+        Self { section, msg }.into()
+    }
+}
+
+impl fmt::Display for SectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for SectionError {}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn location() {
+        let text = "keys\na b ?x\n";
+        let line = text.lines().nth(1).unwrap();
+        let e = ParseError::at_token(Path::new("m.cfg"), 1, line, &line[4..], "bad".to_owned());
+        assert_eq!((e.line, e.col), (2, 5));
+        assert_eq!(e, ParseError::in_text(Path::new("m.cfg"), text, 9, "bad".to_owned()));
+        assert_eq!(e.to_string(), "m.cfg:2:5: bad\n    a b ?x\n        ^");
+    }
+}
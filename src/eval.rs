@@ -1,5 +1,8 @@
+use std::hash::Hash;
+
+use ahash::AHashMap;
 use derive_more::{Deref, DerefMut, Display};
-use eyre::Result as EyreResult;
+use eyre::{Result as EyreResult, eyre};
 use memega::eval::Evaluator;
 use memega::Result;
 use memega::ops::crossover::{crossover_cycle, crossover_order, crossover_pmx};
@@ -8,7 +11,8 @@ use memega::ops::mutation::{mutate_insert, mutate_inversion, mutate_scramble, mu
 use rand::Rng;
 
 use crate::Args;
-use crate::ingest::{load_histograms, load_model};
+use crate::corpus::load_corpus;
+use crate::ingest::{load_histograms, load_histograms_bin};
 use crate::model::{Model, PENALTY};
 use crate::types::{COLEMAK_DHM, Kc};
 
@@ -20,6 +24,176 @@ pub struct Histograms {
     pub trigrams: Vec<((Kc, Kc, Kc), f64)>,
 }
 
+impl Histograms {
+    // Loads from a corpus, a binary histogram file or the data files, in that order of preference.
+    pub fn load(args: &Args) -> EyreResult<Self> {
+        if !args.corpus.is_empty() {
+            load_corpus(&args.corpus, &args.corpus_cfg, &args.cache_dir)
+        } else if let Some(p) = &args.histograms_path {
+            load_histograms_bin(p)
+        } else {
+            load_histograms(&args.unigrams_path, &args.bigrams_path, &args.trigrams_path)
+        }
+    }
+
+    // Loads as above, then prunes. Also returns how much of each histogram was kept, if
+    // pruning is enabled.
+    pub fn from_args(args: &Args) -> EyreResult<(Self, Option<[Retained; 3]>)> {
+        args.prune_cfg.validate()?;
+        let mut hist = Self::load(args)?;
+        let retained = args.prune_cfg.enabled().then(|| hist.prune(&args.prune_cfg));
+        Ok((hist, retained))
+    }
+
+    // Weighted mix of several histograms. Weights and the result are renormalised to sum to one.
+    pub fn combine(sets: &[(f64, Histograms)]) -> EyreResult<Self> {
+        fn mix<K: Copy + Eq + Hash>(
+            sets: &[(f64, Histograms)],
+            grams: impl Fn(&Histograms) -> &[(K, f64)],
+        ) -> Vec<(K, f64)> {
+            let mut out: AHashMap<K, f64> = AHashMap::new();
+            // Zero weight sets contribute nothing, not even zero proportion n-grams.
+            for (weight, hist) in sets.iter().filter(|&&(w, _)| w > 0.0) {
+                for &(k, v) in grams(hist) {
+                    *out.entry(k).or_default() += weight * v;
+                }
+            }
+            let total: f64 = out.values().sum();
+            if total <= 0.0 {
+                return Vec::new();
+            }
+            out.into_iter().map(|(k, v)| (k, v / total)).collect()
+        }
+
+        if let Some((i, &(w, _))) = sets.iter().enumerate().find(|(_, (w, _))| !w.is_finite()) {
+            return Err(eyre!("weight {w} of set {i} must be finite"));
+        }
+        if sets.iter().any(|&(w, _)| w < 0.0) || sets.iter().all(|&(w, _)| w == 0.0) {
+            return Err(eyre!("weights must be non-negative and not all zero"));
+        }
+        Ok(Self {
+            unigrams: mix(sets, |h| &h.unigrams),
+            bigrams: mix(sets, |h| &h.bigrams),
+            trigrams: mix(sets, |h| &h.trigrams),
+        })
+    }
+
+    // Drops rare n-grams to speed up evaluation. Returns how much of each of the
+    // unigram, bigram and trigram histograms was kept.
+    pub fn prune(&mut self, cfg: &PruneCfg) -> [Retained; 3] {
+        fn prune<K>(grams: &mut Vec<(K, f64)>, cfg: &PruneCfg) -> Retained {
+            let total_mass: f64 = grams.iter().map(|&(_, v)| v).sum();
+            let total = grams.len();
+            grams.sort_by(|a, b| b.1.total_cmp(&a.1));
+            let mut mass = 0.0;
+            let mut kept = 0;
+            for &(_, v) in grams.iter() {
+                let covered = cfg.coverage.is_some_and(|c| mass >= c * total_mass);
+                if covered || cfg.min_freq.is_some_and(|f| v < f) {
+                    break;
+                }
+                mass += v;
+                kept += 1;
+            }
+            grams.truncate(kept);
+            if cfg.renormalize && mass > 0.0 {
+                for (_, v) in grams.iter_mut() {
+                    *v /= mass;
+                }
+            }
+            let mass = if total_mass > 0.0 { mass / total_mass } else { 1.0 };
+            Retained { kept, total, mass }
+        }
+
+        [
+            prune(&mut self.unigrams, cfg),
+            prune(&mut self.bigrams, cfg),
+            prune(&mut self.trigrams, cfg),
+        ]
+    }
+}
+
+#[must_use]
+#[derive(Debug, Clone, Default, PartialEq, clap::Args)]
+pub struct PruneCfg {
+    #[clap(
+        long,
+        global = true,
+        value_name = "FRACTION",
+        help = "Keep only the most frequent n-grams covering this fraction of each histogram"
+    )]
+    pub coverage: Option<f64>,
+
+    #[clap(
+        long,
+        global = true,
+        value_name = "PROPORTION",
+        help = "Drop n-grams less frequent than this"
+    )]
+    pub min_freq: Option<f64>,
+
+    #[clap(long, global = true, help = "Renormalise pruned histograms to sum to one")]
+    pub renormalize: bool,
+}
+
+impl PruneCfg {
+    #[must_use]
+    pub fn enabled(&self) -> bool {
+        self.coverage.is_some() || self.min_freq.is_some()
+    }
+
+    pub fn validate(&self) -> EyreResult<()> {
+        if let Some(c) = self.coverage.filter(|&c| c.is_nan() || c <= 0.0 || c > 1.0) {
+            return Err(eyre!("coverage {c} must be more than 0 and at most 1"));
+        }
+        if let Some(f) = self.min_freq.filter(|&f| f.is_nan() || f < 0.0) {
+            return Err(eyre!("min_freq {f} must be at least 0"));
+        }
+        Ok(())
+    }
+}
+
+// How much of a histogram was kept by pruning.
+#[must_use]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Display)]
+#[display("kept {kept} of {total} ({:.3}% of the distribution)", mass * 100.0)]
+pub struct Retained {
+    pub kept: usize,
+    pub total: usize,
+    pub mass: f64,
+}
+
+// Breakdown of the cost of a layout, lower is better.
+#[must_use]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Display)]
+#[display(
+    "unigram: {unigram:.6}\nbigram: {bigram:.6}\ntrigram: {trigram:.6}\n\
+     finger load: {finger_load:.6}\nhand balance: {hand_balance:.6}\n\
+     constraints: {constraints:.6}\ntie break: {tie_break:.6}"
+)]
+pub struct Cost {
+    pub unigram: f64,
+    pub bigram: f64,
+    pub trigram: f64,
+    pub finger_load: f64,
+    pub hand_balance: f64,
+    pub constraints: f64, // Penalties for broken key placement constraints.
+    pub tie_break: f64,   // Similarity to an existing layout.
+}
+
+impl Cost {
+    #[must_use]
+    pub fn total(&self) -> f64 {
+        self.unigram
+            + self.bigram
+            + self.trigram
+            + self.finger_load
+            + self.hand_balance
+            + self.constraints
+            + self.tie_break
+    }
+}
+
 #[must_use]
 #[derive(Debug, Clone)]
 pub struct LayoutEval {
@@ -29,10 +203,70 @@ pub struct LayoutEval {
 }
 
 impl LayoutEval {
-    pub fn from_args(args: &Args) -> EyreResult<Self> {
-        let model = load_model(&args.model_path)?;
-        let hist = load_histograms(&args.unigrams_path, &args.bigrams_path, &args.trigrams_path)?;
-        Ok(Self { model, hist, match_keys: COLEMAK_DHM.to_vec() })
+    pub fn new(model: Model, hist: Histograms) -> Self {
+        Self { model, hist, match_keys: COLEMAK_DHM.to_vec() }
+    }
+
+    pub fn cost(&self, s: &KeyState) -> Cost {
+        let mut cost = Cost {
+            unigram: self.model.unigram_cost(s, &self.hist.unigrams),
+            bigram: self.model.bigram_cost(s, &self.hist.bigrams),
+            trigram: self.model.trigram_cost(s, &self.hist.trigrams),
+            finger_load: self.model.finger_load_cost(s, &self.hist.unigrams),
+            hand_balance: self.model.hand_balance_cost(s, &self.hist.unigrams),
+            ..Default::default()
+        };
+
+        #[must_use]
+        struct Cons {
+            a: Kc,
+            b: Kc,
+            horiz: bool,
+            ordered: bool,
+        }
+
+        let horiz = [
+            Cons { a: Kc::Comma, b: Kc::Dot, horiz: true, ordered: true }, // Keep , and . next to eachother.
+            Cons { a: Kc::DoubleQuote, b: Kc::Quote, horiz: false, ordered: false }, // " and ' vert.
+            Cons { a: Kc::Asterisk, b: Kc::Ampersand, horiz: false, ordered: false }, // * and & vert.
+            Cons { a: Kc::Ampersand, b: Kc::Bar, horiz: false, ordered: false }, // | and & vert.
+            Cons { a: Kc::Minus, b: Kc::Plus, horiz: false, ordered: false },    // - and + vert.
+        ];
+        for Cons { a, b, horiz, ordered } in horiz {
+            let apos = s.iter().position(|&v| v == a);
+            let bpos = s.iter().position(|&v| v == b);
+            if bpos.is_none() || apos.is_none() {
+                continue;
+            }
+            let apos = apos.unwrap();
+            let bpos = bpos.unwrap();
+            let (ab, ba) = if horiz {
+                (apos + 1 == bpos, bpos + 1 == apos)
+            } else {
+                let abelow = self.model.key_below(apos);
+                let bbelow = self.model.key_below(bpos);
+                let ab = if let Some(abelow) = abelow { abelow == bpos } else { false };
+                let ba = if let Some(bbelow) = bbelow { bbelow == apos } else { false };
+                (ab, ba)
+            };
+            if (!ba || ordered) && !ab {
+                cost.constraints += PENALTY;
+            }
+        }
+
+        // Check fixed keys
+        for (i, &kc) in self.model.fixed.iter().enumerate() {
+            if kc != Kc::None && kc != s[i] {
+                cost.constraints += PENALTY;
+            }
+        }
+
+        cost.constraints += self.model.layer_capacity_cost(s);
+
+        // Tie-breaking: similarity to given existing layout:
+        cost.tie_break = count_different(s, &self.match_keys) as f64 / 100000.0;
+
+        cost
     }
 }
 
@@ -99,61 +333,8 @@ impl Evaluator for LayoutEval {
     }
 
     fn fitness(&self, s: &Self::State, _data: &Self::Data) -> Result<f64> {
-        let mut cost = 0.0;
-
-        cost += self.model.unigram_cost(s, &self.hist.unigrams);
-        cost += self.model.bigram_cost(s, &self.hist.bigrams);
-        cost += self.model.trigram_cost(s, &self.hist.trigrams);
-
-        #[must_use]
-        struct Cons {
-            a: Kc,
-            b: Kc,
-            horiz: bool,
-            ordered: bool,
-        }
-
-        let horiz = [
-            Cons { a: Kc::Comma, b: Kc::Dot, horiz: true, ordered: true }, // Keep , and . next to eachother.
-            Cons { a: Kc::DoubleQuote, b: Kc::Quote, horiz: false, ordered: false }, // " and ' vert.
-            Cons { a: Kc::Asterisk, b: Kc::Ampersand, horiz: false, ordered: false }, // * and & vert.
-            Cons { a: Kc::Ampersand, b: Kc::Bar, horiz: false, ordered: false }, // | and & vert.
-            Cons { a: Kc::Minus, b: Kc::Plus, horiz: false, ordered: false },    // - and + vert.
-        ];
-        for Cons { a, b, horiz, ordered } in horiz {
-            let apos = s.iter().position(|&v| v == a);
-            let bpos = s.iter().position(|&v| v == b);
-            if bpos.is_none() || apos.is_none() {
-                continue;
-            }
-            let apos = apos.unwrap();
-            let bpos = bpos.unwrap();
-            let (ab, ba) = if horiz {
-                (apos + 1 == bpos, bpos + 1 == apos)
-            } else {
-                let abelow = self.model.key_below(apos);
-                let bbelow = self.model.key_below(bpos);
-                let ab = if let Some(abelow) = abelow { abelow == bpos } else { false };
-                let ba = if let Some(bbelow) = bbelow { bbelow == apos } else { false };
-                (ab, ba)
-            };
-            if (!ba || ordered) && !ab {
-                cost += PENALTY;
-            }
-        }
-
-        // Check fixed keys
-        for (i, &kc) in self.model.fixed.iter().enumerate() {
-            if kc != Kc::None && kc != s[i] {
-                cost += PENALTY;
-            }
-        }
-
-        // Tie-breaking: similarity to given existing layout:
-        cost += count_different(s, &self.match_keys) as f64 / 100000.0;
-
         // 1.0 / (cost + 1.0)
-        Ok((-cost).exp())
+        Ok((-self.cost(s).total()).exp())
     }
 
     fn distance(&self, s1: &Self::State, s2: &Self::State) -> Result<f64> {
@@ -164,3 +345,61 @@ impl Evaluator for LayoutEval {
         Ok(d)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn combine() {
+        let a = Histograms { unigrams: vec![(Kc::A, 1.0)], bigrams: vec![], trigrams: vec![] };
+        let b = Histograms {
+            unigrams: vec![(Kc::A, 0.5), (Kc::B, 0.5)],
+            bigrams: vec![((Kc::A, Kc::B), 1.0)],
+            trigrams: vec![],
+        };
+        let mut hist = Histograms::combine(&[(3.0, a), (1.0, b)]).unwrap();
+        hist.unigrams.sort_by_key(|x| x.0);
+        assert_relative_eq!(0.875, hist.unigrams[0].1);
+        assert_relative_eq!(0.125, hist.unigrams[1].1);
+        // Renormalised, since the first set has no bigrams.
+        assert_relative_eq!(1.0, hist.bigrams[0].1);
+        assert!(hist.trigrams.is_empty());
+
+        // Only the zero weight set has bigrams, so there are none.
+        let a = Histograms { unigrams: vec![(Kc::A, 1.0)], bigrams: vec![], trigrams: vec![] };
+        let b = Histograms { bigrams: vec![((Kc::A, Kc::B), 1.0)], ..a.clone() };
+        let mixed = Histograms::combine(&[(1.0, a), (0.0, b)]).unwrap();
+        assert!(mixed.bigrams.is_empty());
+        assert_relative_eq!(1.0, mixed.unigrams[0].1);
+
+        let nan = Histograms { unigrams: vec![(Kc::A, 1.0)], bigrams: vec![], trigrams: vec![] };
+        let err = Histograms::combine(&[(1.0, hist), (f64::NAN, nan)]).unwrap_err();
+        assert_eq!(err.to_string(), "weight NaN of set 1 must be finite");
+    }
+
+    #[test]
+    fn prune() {
+        let unigrams = vec![(Kc::A, 0.5), (Kc::B, 0.3), (Kc::C, 0.15), (Kc::D, 0.05)];
+        let mut hist = Histograms { unigrams, bigrams: vec![], trigrams: vec![] };
+        let cfg = PruneCfg { coverage: Some(0.8), ..Default::default() };
+        let [uni, bi, _] = hist.clone().prune(&cfg);
+        assert_eq!((uni.kept, uni.total), (2, 4));
+        assert_relative_eq!(0.8, uni.mass);
+        assert_relative_eq!(1.0, bi.mass);
+
+        for coverage in [0.0, -0.5, 1.5, f64::NAN] {
+            assert!(
+                PruneCfg { coverage: Some(coverage), ..Default::default() }.validate().is_err()
+            );
+        }
+        assert!(PruneCfg { min_freq: Some(-0.1), ..Default::default() }.validate().is_err());
+
+        let cfg = PruneCfg { min_freq: Some(0.1), renormalize: true, ..Default::default() };
+        let [uni, _, _] = hist.prune(&cfg);
+        assert_relative_eq!(0.95, uni.mass);
+        assert_relative_eq!(0.5 / 0.95, hist.unigrams[0].1);
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::Write;
+
+use eyre::{Result, eyre};
+
+use crate::model::Model;
+use crate::types::Kc;
+
+// Template for one layer's LAYOUT(...) arguments, with a comma separated X for
+// each key of the model layout.
+#[must_use]
+pub fn qmk_template(model: &Model) -> String {
+    let mut keys = model.layout.matches('X').count();
+    let mut s = String::new();
+    for c in model.layout.trim_end().chars() {
+        s.push(c);
+        if c == 'X' {
+            keys -= 1;
+            if keys > 0 {
+                s.push(',');
+            }
+        }
+    }
+    s
+}
+
+// Byte offsets of the placeholders in a template: each X which isn't part of a
+// longer name such as KC_X.
+fn placeholders(template: &str) -> Vec<usize> {
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+    template
+        .char_indices()
+        .filter(|&(i, c)| {
+            c == 'X'
+                && !word(template[..i].chars().next_back())
+                && !word(template[i + 1..].chars().next())
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// QMK keymap.c for layout |l|, with each layer's keys filled into |template|.
+// Combos are written as QMK combos of the first layer keys they're typed with.
+pub fn qmk_keymap(model: &Model, l: &[Kc], template: &str) -> Result<String> {
+    if l.len() != model.universe.len() {
+        return Err(eyre!(
+            "layout has {} keys, but the model has {}",
+            l.len(),
+            model.universe.len()
+        ));
+    }
+    let slots = placeholders(template);
+    let per_layer = model.positions();
+    if slots.len() != per_layer {
+        return Err(eyre!(
+            "template has {} placeholders, but the model has {per_layer} keys per layer",
+            slots.len()
+        ));
+    }
+    let (keys, combos) = l.split_at(l.len() - model.combos.len());
+
+    let mut s = String::from("#include QMK_KEYBOARD_H\n\n");
+    s += "const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n";
+    for (layer, keys) in keys.chunks(per_layer).enumerate() {
+        let mut filled = String::new();
+        let mut last = 0;
+        for (&i, kc) in slots.iter().zip(keys) {
+            filled += &template[last..i];
+            filled += &kc.qmk();
+            last = i + 1;
+        }
+        filled += &template[last..];
+        let _ = writeln!(s, "    [{layer}] = LAYOUT(");
+        for line in filled.lines() {
+            let line = line.trim_end();
+            if !line.is_empty() {
+                let _ = writeln!(s, "        {line}");
+            }
+        }
+        s += "    ),\n";
+    }
+    s += "};\n";
+
+    if !model.combos.is_empty() {
+        // Needs COMBO_ENABLE = yes in rules.mk.
+        s += "\n";
+        for (c, &(a, b)) in model.combos.iter().enumerate() {
+            if keys[a] == Kc::None || keys[b] == Kc::None {
+                return Err(eyre!("combo {a} + {b} uses a position with no first layer key"));
+            }
+            let _ = writeln!(
+                s,
+                "const uint16_t PROGMEM combo{c}[] = {{{}, {}, COMBO_END}};",
+                keys[a].qmk(),
+                keys[b].qmk()
+            );
+        }
+        s += "\ncombo_t key_combos[] = {\n";
+        for (c, kc) in combos.iter().enumerate() {
+            let _ = writeln!(s, "    COMBO(combo{c}, {}),", kc.qmk());
+        }
+        s += "};\n";
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn keymap() {
+        let model = Model {
+            layout: "X X  X\nX X  X\n".into(),
+            layers: 2,
+            combos: vec![(0, 1)],
+            universe: vec![
+                Kc::X,
+                Kc::Hash,
+                Kc::Num1,
+                Kc::LeftParen,
+                Kc::Mo1,
+                Kc::F1,
+                Kc::None,
+                Kc::Escape,
+                Kc::RightParen,
+                Kc::Shift,
+                Kc::None,
+                Kc::None,
+                Kc::Minus,
+            ],
+            ..Model::default()
+        };
+        let template = qmk_template(&model);
+        assert_eq!(template, "X, X,  X,\nX, X,  X");
+        let l = model.universe.clone();
+        assert_eq!(
+            qmk_keymap(&model, &l, &template).unwrap(),
+            "#include QMK_KEYBOARD_H
+
+const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {
+    [0] = LAYOUT(
+        KC_X, KC_HASH,  KC_1,
+        KC_LPRN, MO(1),  KC_F1
+    ),
+    [1] = LAYOUT(
+        KC_NO, KC_ESC,  KC_RPRN,
+        KC_LSFT, KC_NO,  KC_NO
+    ),
+};
+
+const uint16_t PROGMEM combo0[] = {KC_X, KC_HASH, COMBO_END};
+
+combo_t key_combos[] = {
+    COMBO(combo0, KC_MINS),
+};
+"
+        );
+
+        // Board keys outside the model are kept, and names containing X aren't
+        // placeholders.
+        let template = "KC_X, X, X, X,\nKC_NO, X, X, X";
+        let out = qmk_keymap(&model, &l, template).unwrap();
+        assert!(out.contains(
+            "        KC_X, KC_X, KC_HASH, KC_1,\n        KC_NO, KC_LPRN, MO(1), KC_F1\n"
+        ));
+        assert!(qmk_keymap(&model, &l, "X, X").is_err());
+        assert!(qmk_keymap(&model, &l[1..], template).is_err());
+    }
+}
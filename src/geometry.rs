@@ -0,0 +1,111 @@
+use eyre::{Result, eyre};
+
+// Costs derived from physical key positions, so the same keys can be evaluated
+// on row-staggered, ortholinear and column-staggered boards.
+pub(crate) const KEY_UNIT: f64 = 19.05; // Standard key pitch in mm.
+const TRAVEL: f64 = 1.5; // Cost per key unit travelled away from the home key.
+const SAME_FINGER: f64 = 1.0; // Using one finger for two different keys is hard.
+const SAME_FINGER_TRAVEL: f64 = 0.5; // Extra same finger cost per key unit travelled.
+const ROLL_IN: f64 = -0.5; // Rolling towards the index finger is easy.
+const ROLL_OUT: f64 = 0.2; // Rolling towards the pinkie is a bit harder.
+const JUMP: f64 = 0.6; // Cost per key unit of vertical movement not explained by stagger.
+
+// Effort multiplier for index, middle, ring, pinkie, thumb.
+pub const DEFAULT_FINGER_STRENGTH: [f64; 5] = [1.0, 1.0, 1.2, 1.6, 1.0];
+
+#[must_use]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Geometry {
+    pub pos: Vec<(f64, f64)>, // (x, y) of each key in mm. y increases towards the top row.
+    pub home: Vec<usize>,     // Home key of the finger used for each key.
+    pub strength: Vec<f64>,   // Effort multiplier for each finger.
+}
+
+impl Geometry {
+    pub fn new(
+        pos: Vec<(f64, f64)>,
+        is_home: &[bool],
+        hand: &[i32],
+        finger: &[i32],
+        strength: Vec<f64>,
+    ) -> Result<Self> {
+        if pos.len() != hand.len() || pos.len() != finger.len() || pos.len() != is_home.len() {
+            return Err(eyre!("geometry must have a position and home flag for every key"));
+        }
+        let mut home = Vec::with_capacity(pos.len());
+        for v in 0..pos.len() {
+            let h = (0..pos.len())
+                .find(|&i| is_home[i] && hand[i] == hand[v] && finger[i] == finger[v])
+                .ok_or_else(|| eyre!("no home key for hand {} finger {}", hand[v], finger[v]))?;
+            home.push(h);
+        }
+        for &f in finger {
+            if f < 0 || f as usize >= strength.len() {
+                return Err(eyre!("no finger strength for finger {}", f));
+            }
+        }
+        Ok(Self { pos, home, strength })
+    }
+
+    fn dist(&self, a: usize, b: usize) -> f64 {
+        let (ax, ay) = self.pos[a];
+        let (bx, by) = self.pos[b];
+        (ax - bx).hypot(ay - by) / KEY_UNIT
+    }
+
+    #[must_use]
+    pub fn unigram_cost(&self, v: usize, finger: i32) -> f64 {
+        self.strength[finger as usize] * (1.0 + TRAVEL * self.dist(v, self.home[v]))
+    }
+
+    // Assumes both keys are on the same hand and are different keys.
+    #[must_use]
+    pub fn bigram_cost(&self, prev: usize, pfing: i32, cur: usize, cfing: i32) -> f64 {
+        if pfing == cfing {
+            let strength = self.strength[cfing as usize];
+            return strength * (SAME_FINGER + SAME_FINGER_TRAVEL * self.dist(prev, cur));
+        }
+        // Vertical movement relative to the offset between the two home keys, so
+        // moving along a staggered column is free.
+        let stagger = self.pos[self.home[cur]].1 - self.pos[self.home[prev]].1;
+        let jump = ((self.pos[cur].1 - self.pos[prev].1 - stagger) / KEY_UNIT).abs();
+        let strength = self.strength[pfing as usize].max(self.strength[cfing as usize]);
+        let roll = if cfing < pfing { ROLL_IN } else { ROLL_OUT };
+        roll + JUMP * strength * jump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    #[test]
+    fn stagger() {
+        // Left hand middle and index finger columns, middle column staggered up half a key.
+        // 1 0
+        // A
+        //   B
+        // C
+        //   D
+        let u = KEY_UNIT;
+        let g = Geometry::new(
+            vec![(0.0, 1.5 * u), (u, u), (0.0, 0.5 * u), (u, 0.0)],
+            &[false, false, true, true],
+            &[0, 0, 0, 0],
+            &[1, 0, 1, 0],
+            DEFAULT_FINGER_STRENGTH.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(g.home, vec![2, 3, 2, 3]);
+        assert_relative_eq!(1.0, g.unigram_cost(2, 1));
+        assert_relative_eq!(1.0 + TRAVEL, g.unigram_cost(0, 1));
+        // Moving along the stagger costs nothing extra.
+        assert_relative_eq!(ROLL_IN, g.bigram_cost(2, 1, 3, 0));
+        assert_relative_eq!(ROLL_OUT, g.bigram_cost(1, 0, 0, 1));
+        // Moving against it does.
+        assert_relative_eq!(ROLL_IN + JUMP, g.bigram_cost(0, 1, 3, 0));
+        assert_relative_eq!(SAME_FINGER + SAME_FINGER_TRAVEL, g.bigram_cost(0, 1, 2, 1));
+    }
+}
@@ -1,31 +1,67 @@
+use std::fmt::{self, Write};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use eyre::{Result, WrapErr, eyre};
 
+use crate::cfg::ModelCfg;
+use crate::error::{ParseError, SectionError};
 use crate::eval::{Histograms, KeyState};
-use crate::model::Model;
+use crate::model::{Model, Penalty, PenaltyShape};
+use crate::preset::Preset;
 use crate::types::Kc;
 
 #[must_use]
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 enum ParseStage {
+    Include,
+    Preset,
     Layout,
+    Layers,
+    LayerCost,
+    LayerSwitchCost,
+    LayerCapacity,
+    Combos,
+    ChordCost,
     Keys,
     Fixed,
     UnigramCost,
     BigramCost,
+    LeftUnigramCost,
+    RightUnigramCost,
+    LeftBigramCost,
+    RightBigramCost,
+    ThumbBigramCost,
+    ShiftSameHand,
+    ShiftPairs,
+    ShiftCost,
     Row,
     Hand,
     Finger,
+    PosX,
+    PosY,
+    Home,
+    FingerStrength,
+    FingerLoad,
+    FingerLoadPenalty,
+    HandBalance,
+    HandBalancePenalty,
 }
 
-pub fn load_seeds<P: AsRef<Path>>(layout_path: P) -> Result<Vec<KeyState>> {
+// Loads layouts separated by blank lines, each of which must have |size| keys.
+pub fn load_seeds<P: AsRef<Path>>(layout_path: P, size: usize) -> Result<Vec<KeyState>> {
+    let path = layout_path.as_ref();
+    let text = fs::read_to_string(path)?;
     let mut keys = Vec::new();
     let mut layouts = Vec::new();
-    for i in fs::read_to_string(layout_path)?.lines() {
-        if i.is_empty() {
+    let mut starts = Vec::new(); // Line each layout starts on, for errors.
+    for (idx, i) in text.lines().enumerate() {
+        if is_comment(i) {
+            check_hash_row(path, idx, i)?;
+            continue;
+        }
+        if i.trim().is_empty() {
             layouts.push(KeyState(keys.clone()));
             keys.clear();
         }
@@ -33,54 +69,230 @@ pub fn load_seeds<P: AsRef<Path>>(layout_path: P) -> Result<Vec<KeyState>> {
             if kc.is_empty() {
                 continue;
             }
-            keys.push(Kc::from_str(kc).wrap_err(eyre!("could not find {}", kc))?);
+            if keys.is_empty() {
+                starts.push((idx, i));
+            }
+            keys.push(parse_at(path, idx, i, kc, "key")?);
         }
     }
     if !keys.is_empty() {
         layouts.push(KeyState(keys));
     }
-    for v in &layouts {
-        if v.len() != layouts[0].len() {
-            return Err(eyre!("not all layouts the same size"));
+    layouts.retain(|l| !l.is_empty());
+    if layouts.is_empty() {
+        return Err(eyre!("no layouts in {}", path.display()));
+    }
+    for (v, &(idx, line)) in layouts.iter().zip(&starts) {
+        if v.len() != size {
+            let msg = format!("layout has {} keys, but the model has {size}", v.len());
+            return Err(ParseError::at_offset(path, idx, line, 0, msg).into());
         }
     }
     Ok(layouts)
 }
 
+// Parses |token| from |line|, the |idx|th line of |path|, reporting where it is if it's bad.
+fn parse_at<T: FromStr>(
+    path: &Path,
+    idx: usize,
+    line: &str,
+    token: &str,
+    what: &str,
+) -> Result<T, ParseError>
+where
+    T::Err: fmt::Display,
+{
+    token.parse().map_err(|e| {
+        ParseError::at_token(path, idx, line, token, format!("bad {what} {token:?}: {e}"))
+    })
+}
+
+fn parse_penalty(tokens: &[String], name: &str) -> Result<Option<Penalty>> {
+    match tokens {
+        [] => Ok(None),
+        [shape, weight] => Ok(Some(Penalty {
+            shape: PenaltyShape::from_str(shape)
+                .wrap_err(eyre!("unknown penalty shape {}", shape))?,
+            weight: weight.parse::<f64>()?,
+        })),
+        _ => Err(eyre!("{} must be a shape and a weight", name)),
+    }
+}
+
 pub fn load_model<P: AsRef<Path>>(cfg_path: P) -> Result<Model> {
+    let p = cfg_path.as_ref();
+    load_model_cfg(p)?
+        .build()
+        .map_err(|e| match e.downcast_ref::<SectionError>() {
+            Some(err) => locate_section(p, err.section, &err.msg).map_or(e, Into::into),
+            None => e,
+        })
+        .wrap_err_with(|| eyre!("invalid model {}", p.display()))
+}
+
+// Points at |section| in the model file |p| or, failing that, the files it includes.
+fn locate_section(p: &Path, section: &str, msg: &str) -> Option<ParseError> {
+    let (text, cfg) = read_model_cfg(p).ok()?;
+    let legacy = !matches!(p.extension().and_then(|e| e.to_str()), Some("toml" | "json"));
+    for (idx, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let found = if legacy {
+            !is_comment(line) && line.trim_end() == section
+        } else {
+            // A TOML key or a JSON member name.
+            let (quoted, rest) = match trimmed.strip_prefix('"') {
+                Some(rest) => (true, rest),
+                None => (false, trimmed),
+            };
+            let rest = rest.strip_prefix(section).unwrap_or_default();
+            let rest = if quoted { rest.strip_prefix('"').unwrap_or_default() } else { rest };
+            rest.trim_start().starts_with(['=', ':'])
+        };
+        if found {
+            let offset = line.len() - trimmed.len();
+            return Some(ParseError::at_offset(p, idx, line, offset, msg.to_owned()));
+        }
+    }
+    let include = p.parent().unwrap_or_else(|| Path::new("")).join(cfg.include?);
+    locate_section(&include, section, msg)
+}
+
+// Loads a model config as TOML or JSON if it has that extension, otherwise in
+// the legacy format. Included models are loaded and merged underneath it.
+pub fn load_model_cfg<P: AsRef<Path>>(cfg_path: P) -> Result<ModelCfg> {
+    load_model_cfg_from(cfg_path.as_ref(), &mut Vec::new())
+}
+
+// |stack| holds the files currently being loaded, to catch include cycles.
+fn load_model_cfg_from(p: &Path, stack: &mut Vec<PathBuf>) -> Result<ModelCfg> {
+    let canonical = fs::canonicalize(p).wrap_err_with(|| eyre!("can't read {}", p.display()))?;
+    if stack.contains(&canonical) {
+        return Err(eyre!("{} includes itself", p.display()));
+    }
+    let (_, mut cfg) = read_model_cfg(p)?;
+    let Some(include) = cfg.include.take() else {
+        return Ok(cfg);
+    };
+    // Includes are relative to the including file.
+    let include = p.parent().unwrap_or_else(|| Path::new("")).join(include);
+    stack.push(canonical);
+    let base = load_model_cfg_from(&include, stack)
+        .wrap_err_with(|| eyre!("included from {}", p.display()))?;
+    stack.pop();
+    Ok(base.merge(cfg))
+}
+
+// Parses one model file, without loading its include.
+fn read_model_cfg(p: &Path) -> Result<(String, ModelCfg)> {
+    let text = fs::read_to_string(p)?;
+    let cfg = match p.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(|e| {
+            let offset = e.span().map_or(0, |span| span.start);
+            ParseError::in_text(p, &text, offset, e.message().to_owned())
+        })?,
+        Some("json") => serde_json::from_str(&text).map_err(|e| {
+            let idx = e.line().saturating_sub(1);
+            let line = text.lines().nth(idx).unwrap_or_default();
+            ParseError::at_offset(p, idx, line, e.column().saturating_sub(1), e.to_string())
+        })?,
+        _ => parse_legacy_model(&text, p)?,
+    };
+    Ok((text, cfg))
+}
+
+pub fn write_model_cfg<P: AsRef<Path>>(cfg: &ModelCfg, cfg_path: P) -> Result<()> {
+    let p = cfg_path.as_ref();
+    let text = match p.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::to_string_pretty(cfg)?,
+        Some("json") => serde_json::to_string_pretty(cfg)? + "\n",
+        _ => format_legacy_model(cfg),
+    };
+    fs::write(p, text)?;
+    Ok(())
+}
+
+fn parse_legacy_model(text: &str, path: &Path) -> Result<ModelCfg> {
     let mut state = ParseStage::Layout;
+    let mut section = "layout";
+    let mut include = Vec::new();
+    let mut preset = None;
     let mut layout = String::new();
+    let mut layers = Vec::new();
+    let mut layer_cost = Vec::new();
+    let mut layer_switch_cost = Vec::new();
+    let mut layer_capacity = Vec::new();
+    let mut combos = Vec::new();
+    let mut chord_cost = Vec::new();
     let mut keys = Vec::new();
     let mut fixed = Vec::new();
     let mut unigram_cost = Vec::new();
-    let mut bigram_cost = [[[0.0; 5]; 4]; 4];
-    let mut bigram_idx = 0;
+    let mut bigram_cost = Vec::new();
+    let mut left_unigram_cost = Vec::new();
+    let mut right_unigram_cost = Vec::new();
+    let mut left_bigram_cost = Vec::new();
+    let mut right_bigram_cost = Vec::new();
+    let mut thumb_bigram_cost = Vec::new();
+    let mut shift_same_hand = Vec::new();
+    let mut shift_pairs = Vec::new();
+    let mut shift_cost = Vec::new();
     let mut row = Vec::new();
     let mut hand = Vec::new();
     let mut finger = Vec::new();
-    for i in fs::read_to_string(cfg_path)?.lines() {
-        let mut updated = true;
-        if i.starts_with("layout") {
-            state = ParseStage::Layout;
-        } else if i.starts_with("keys") {
-            state = ParseStage::Keys;
-        } else if i.starts_with("fixed") {
-            state = ParseStage::Fixed;
-        } else if i.starts_with("unigram_cost") {
-            state = ParseStage::UnigramCost;
-        } else if i.starts_with("bigram_cost") {
-            state = ParseStage::BigramCost;
-        } else if i.starts_with("row") {
-            state = ParseStage::Row;
-        } else if i.starts_with("hand") {
-            state = ParseStage::Hand;
-        } else if i.starts_with("finger") {
-            state = ParseStage::Finger;
-        } else {
-            updated = false;
+    let mut pos_x = Vec::new();
+    let mut pos_y = Vec::new();
+    let mut home = Vec::new();
+    let mut finger_strength = Vec::new();
+    let mut finger_load = Vec::new();
+    let mut finger_load_penalty = Vec::new();
+    let mut hand_balance = Vec::new();
+    let mut hand_balance_penalty = Vec::new();
+    let mut headers = Vec::new(); // Line of each section header, for errors.
+    for (idx, i) in text.lines().enumerate() {
+        if is_comment(i) {
+            if matches!(state, ParseStage::Keys | ParseStage::Fixed) {
+                check_hash_row(path, idx, i)?;
+            }
+            continue;
         }
-
-        if updated {
+        let next = match i.trim_end() {
+            "include" => Some(ParseStage::Include),
+            "preset" => Some(ParseStage::Preset),
+            "layout" => Some(ParseStage::Layout),
+            "layers" => Some(ParseStage::Layers),
+            "layer_cost" => Some(ParseStage::LayerCost),
+            "layer_switch_cost" => Some(ParseStage::LayerSwitchCost),
+            "layer_capacity" => Some(ParseStage::LayerCapacity),
+            "combos" => Some(ParseStage::Combos),
+            "chord_cost" => Some(ParseStage::ChordCost),
+            "keys" => Some(ParseStage::Keys),
+            "fixed" => Some(ParseStage::Fixed),
+            "unigram_cost" => Some(ParseStage::UnigramCost),
+            "bigram_cost" => Some(ParseStage::BigramCost),
+            "left_unigram_cost" => Some(ParseStage::LeftUnigramCost),
+            "right_unigram_cost" => Some(ParseStage::RightUnigramCost),
+            "left_bigram_cost" => Some(ParseStage::LeftBigramCost),
+            "right_bigram_cost" => Some(ParseStage::RightBigramCost),
+            "thumb_bigram_cost" => Some(ParseStage::ThumbBigramCost),
+            "shift_same_hand" => Some(ParseStage::ShiftSameHand),
+            "shift_pairs" => Some(ParseStage::ShiftPairs),
+            "shift_cost" => Some(ParseStage::ShiftCost),
+            "row" => Some(ParseStage::Row),
+            "hand" => Some(ParseStage::Hand),
+            "finger" => Some(ParseStage::Finger),
+            "pos_x" => Some(ParseStage::PosX),
+            "pos_y" => Some(ParseStage::PosY),
+            "home" => Some(ParseStage::Home),
+            "finger_strength" => Some(ParseStage::FingerStrength),
+            "finger_load" => Some(ParseStage::FingerLoad),
+            "finger_load_penalty" => Some(ParseStage::FingerLoadPenalty),
+            "hand_balance" => Some(ParseStage::HandBalance),
+            "hand_balance_penalty" => Some(ParseStage::HandBalancePenalty),
+            _ => None,
+        };
+        if let Some(next) = next {
+            state = next;
+            section = i.trim_end();
+            headers.push((section, idx, i));
             continue;
         }
         if state == ParseStage::Layout {
@@ -92,25 +304,208 @@ pub fn load_model<P: AsRef<Path>>(cfg_path: P) -> Result<Model> {
             if s.is_empty() {
                 continue;
             }
-            match state {
-                ParseStage::Layout => {}
-                ParseStage::Keys => keys.push(Kc::from_str(s)?),
-                ParseStage::Fixed => fixed.push(Kc::from_str(s).unwrap_or_default()),
-                ParseStage::UnigramCost => unigram_cost.push(s.parse::<f64>()?),
-                ParseStage::BigramCost => {
-                    bigram_cost[bigram_idx / 5 / 4][bigram_idx / 5 % 4][bigram_idx % 5] =
-                        s.parse::<f64>()?;
-                    bigram_idx += 1;
+            let mut parse = || -> Result<()> {
+                match state {
+                    ParseStage::Layout => {}
+                    ParseStage::Include => include.push(PathBuf::from(s)),
+                    ParseStage::Preset => {
+                        preset = Some(Preset::from_str(s).wrap_err(eyre!("unknown preset {}", s))?);
+                    }
+                    ParseStage::Layers => layers.push(s.parse::<usize>()?),
+                    ParseStage::LayerCost => layer_cost.push(s.parse::<f64>()?),
+                    ParseStage::LayerSwitchCost => layer_switch_cost.push(s.parse::<f64>()?),
+                    ParseStage::LayerCapacity => layer_capacity.push(s.parse::<usize>()?),
+                    ParseStage::Combos => combos.push(s.parse::<usize>()?),
+                    ParseStage::ChordCost => chord_cost.push(s.parse::<f64>()?),
+                    ParseStage::Keys => keys.push(Kc::from_str(s)?),
+                    // X marks a place which isn't fixed, like None.
+                    ParseStage::Fixed if s == "X" => fixed.push(Kc::None),
+                    ParseStage::Fixed => fixed.push(Kc::from_str(s)?),
+                    ParseStage::UnigramCost => unigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::BigramCost => bigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::LeftUnigramCost => left_unigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::RightUnigramCost => right_unigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::LeftBigramCost => left_bigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::RightBigramCost => right_bigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::ThumbBigramCost => thumb_bigram_cost.push(s.parse::<f64>()?),
+                    ParseStage::ShiftSameHand => shift_same_hand.push(s.parse::<f64>()?),
+                    ParseStage::ShiftPairs => shift_pairs.push(s.parse::<bool>()?),
+                    ParseStage::ShiftCost => shift_cost.push(s.parse::<f64>()?),
+                    ParseStage::Row => row.push(s.parse::<i32>()?),
+                    ParseStage::Hand => hand.push(s.parse::<i32>()?),
+                    ParseStage::Finger => finger.push(s.parse::<i32>()?),
+                    ParseStage::PosX => pos_x.push(s.parse::<f64>()?),
+                    ParseStage::PosY => pos_y.push(s.parse::<f64>()?),
+                    ParseStage::Home => home.push(s.parse::<i32>()? != 0),
+                    ParseStage::FingerStrength => finger_strength.push(s.parse::<f64>()?),
+                    ParseStage::FingerLoad => finger_load.push(s.parse::<f64>()?),
+                    ParseStage::FingerLoadPenalty => finger_load_penalty.push(s.to_owned()),
+                    ParseStage::HandBalance => hand_balance.push(s.parse::<f64>()?),
+                    ParseStage::HandBalancePenalty => hand_balance_penalty.push(s.to_owned()),
                 }
-                ParseStage::Row => row.push(s.parse::<i32>()?),
-                ParseStage::Hand => hand.push(s.parse::<i32>()?),
-                ParseStage::Finger => finger.push(s.parse::<i32>()?),
+                Ok(())
+            };
+            parse().map_err(|e| {
+                ParseError::at_token(path, idx, i, s, format!("bad {section} value {s:?}: {e}"))
+            })?;
+        }
+    }
+
+    // Errors about a whole section point at its header.
+    let section_err = |section: &str, msg: &str| -> eyre::Report {
+        match headers.iter().find(|&&(name, _, _)| name == section) {
+            Some(&(_, idx, line)) => {
+                ParseError::at_offset(path, idx, line, 0, msg.to_owned()).into()
             }
+            None => eyre!("{}: {}", path.display(), msg),
         }
+    };
+    if finger_load.len() % 2 != 0 {
+        return Err(section_err("finger_load", "finger_load must be pairs of min and max"));
+    }
+    let finger_load: Vec<_> = finger_load.chunks(2).map(|v| (v[0], v[1])).collect();
+    if combos.len() % 2 != 0 {
+        return Err(section_err("combos", "combos must be pairs of keys"));
+    }
+    let combos: Vec<_> = combos.chunks(2).map(|v| (v[0], v[1])).collect();
+    let chord_cost =
+        single(&chord_cost, section_err("chord_cost", "chord_cost must be a single cost"))?;
+    let finger_load_penalty =
+        parse_penalty(&finger_load_penalty, "finger_load_penalty").map_err(|e| {
+            section_err("finger_load_penalty", &format!("bad finger_load_penalty: {e:#}"))
+        })?;
+    let hand_balance = single(
+        &hand_balance,
+        section_err("hand_balance", "hand_balance must be a single proportion"),
+    )?;
+    let shift_same_hand = single(
+        &shift_same_hand,
+        section_err("shift_same_hand", "shift_same_hand must be a single cost"),
+    )?;
+    let shift_pairs =
+        single(&shift_pairs, section_err("shift_pairs", "shift_pairs must be true or false"))?;
+    let shift_cost =
+        single(&shift_cost, section_err("shift_cost", "shift_cost must be a single cost"))?;
+    let layers = single(&layers, section_err("layers", "layers must be a single count"))?;
+    let layer_cost =
+        single(&layer_cost, section_err("layer_cost", "layer_cost must be a single cost"))?;
+    let layer_switch_cost = single(
+        &layer_switch_cost,
+        section_err("layer_switch_cost", "layer_switch_cost must be a single cost"),
+    )?;
+    let hand_balance_penalty = parse_penalty(&hand_balance_penalty, "hand_balance_penalty")
+        .map_err(|e| {
+            section_err("hand_balance_penalty", &format!("bad hand_balance_penalty: {e:#}"))
+        })?;
+    if include.len() > 1 {
+        return Err(section_err("include", "include must be a single file"));
+    }
+
+    Ok(ModelCfg {
+        include: include.pop(),
+        preset,
+        layout: (!layout.trim().is_empty()).then_some(layout),
+        layers,
+        layer_cost,
+        layer_switch_cost,
+        layer_capacity: (!layer_capacity.is_empty()).then_some(layer_capacity),
+        combos: (!combos.is_empty()).then_some(combos),
+        chord_cost,
+        keys: (!keys.is_empty()).then_some(keys),
+        fixed: (!fixed.is_empty()).then_some(fixed),
+        unigram_cost: (!unigram_cost.is_empty()).then_some(unigram_cost),
+        bigram_cost: (!bigram_cost.is_empty()).then_some(bigram_cost),
+        left_unigram_cost: (!left_unigram_cost.is_empty()).then_some(left_unigram_cost),
+        right_unigram_cost: (!right_unigram_cost.is_empty()).then_some(right_unigram_cost),
+        left_bigram_cost: (!left_bigram_cost.is_empty()).then_some(left_bigram_cost),
+        right_bigram_cost: (!right_bigram_cost.is_empty()).then_some(right_bigram_cost),
+        thumb_bigram_cost: (!thumb_bigram_cost.is_empty()).then_some(thumb_bigram_cost),
+        shift_same_hand,
+        shift_pairs,
+        shift_cost,
+        row: (!row.is_empty()).then_some(row),
+        hand: (!hand.is_empty()).then_some(hand),
+        finger: (!finger.is_empty()).then_some(finger),
+        pos_x: (!pos_x.is_empty()).then_some(pos_x),
+        pos_y: (!pos_y.is_empty()).then_some(pos_y),
+        home: (!home.is_empty()).then_some(home),
+        finger_strength: (!finger_strength.is_empty()).then_some(finger_strength),
+        finger_load: (!finger_load.is_empty()).then_some(finger_load),
+        finger_load_penalty,
+        hand_balance,
+        hand_balance_penalty,
+    })
+}
+
+// Value of a section which holds at most one value, or |err|.
+fn single<T: Copy>(values: &[T], err: eyre::Report) -> Result<Option<T>> {
+    match values {
+        [] => Ok(None),
+        &[v] => Ok(Some(v)),
+        _ => Err(err),
+    }
+}
+
+// Inverse of |parse_legacy_model|.
+fn format_legacy_model(cfg: &ModelCfg) -> String {
+    fn section<T: ToString>(out: &mut String, name: &str, values: Option<&[T]>) {
+        let Some(values) = values else { return };
+        let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+        let _ = write!(out, "{name}\n{}\n\n", values.join(" "));
+    }
+    fn penalty(p: Option<Penalty>) -> Option<Vec<String>> {
+        p.map(|p| vec![p.shape.to_string(), p.weight.to_string()])
     }
-    assert_eq!(bigram_idx, 80, "missing bigram costs");
 
-    Ok(Model { layout, universe: keys, fixed, unigram_cost, bigram_cost, row, hand, finger })
+    let mut out = String::new();
+    let include = cfg.include.as_ref().map(|p| vec![p.display()]);
+    section(&mut out, "include", include.as_deref());
+    section(&mut out, "preset", cfg.preset.map(|p| vec![p]).as_deref());
+    if let Some(layout) = &cfg.layout {
+        let _ = write!(out, "layout\n{}\n\n", layout.trim_end());
+    }
+    let keys = cfg.keys.as_ref().map(|keys| keys.iter().map(|&kc| key_name(kc)));
+    section(&mut out, "layers", cfg.layers.map(|v| vec![v]).as_deref());
+    section(&mut out, "layer_cost", cfg.layer_cost.map(|v| vec![v]).as_deref());
+    section(&mut out, "layer_switch_cost", cfg.layer_switch_cost.map(|v| vec![v]).as_deref());
+    section(&mut out, "layer_capacity", cfg.layer_capacity.as_deref());
+    let combos =
+        cfg.combos.as_ref().map(|c| c.iter().flat_map(|&(a, b)| [a, b]).collect::<Vec<_>>());
+    section(&mut out, "combos", combos.as_deref());
+    section(&mut out, "chord_cost", cfg.chord_cost.map(|v| vec![v]).as_deref());
+    section(&mut out, "keys", keys.map(Iterator::collect::<Vec<_>>).as_deref());
+    // Unfixed keys are written as X, which isn't a key.
+    let fixed = cfg.fixed.as_ref().map(|fixed| {
+        fixed.iter().map(|&kc| if kc == Kc::None { "X".to_owned() } else { key_name(kc) })
+    });
+    section(&mut out, "fixed", fixed.map(Iterator::collect::<Vec<_>>).as_deref());
+    section(&mut out, "unigram_cost", cfg.unigram_cost.as_deref());
+    section(&mut out, "bigram_cost", cfg.bigram_cost.as_deref());
+    section(&mut out, "left_unigram_cost", cfg.left_unigram_cost.as_deref());
+    section(&mut out, "right_unigram_cost", cfg.right_unigram_cost.as_deref());
+    section(&mut out, "left_bigram_cost", cfg.left_bigram_cost.as_deref());
+    section(&mut out, "right_bigram_cost", cfg.right_bigram_cost.as_deref());
+    section(&mut out, "thumb_bigram_cost", cfg.thumb_bigram_cost.as_deref());
+    section(&mut out, "shift_same_hand", cfg.shift_same_hand.map(|v| vec![v]).as_deref());
+    section(&mut out, "shift_pairs", cfg.shift_pairs.map(|v| vec![v]).as_deref());
+    section(&mut out, "shift_cost", cfg.shift_cost.map(|v| vec![v]).as_deref());
+    section(&mut out, "row", cfg.row.as_deref());
+    section(&mut out, "hand", cfg.hand.as_deref());
+    section(&mut out, "finger", cfg.finger.as_deref());
+    section(&mut out, "pos_x", cfg.pos_x.as_deref());
+    section(&mut out, "pos_y", cfg.pos_y.as_deref());
+    let home = cfg.home.as_ref().map(|home| home.iter().map(|&h| i32::from(h)).collect::<Vec<_>>());
+    section(&mut out, "home", home.as_deref());
+    section(&mut out, "finger_strength", cfg.finger_strength.as_deref());
+    let finger_load = cfg
+        .finger_load
+        .as_ref()
+        .map(|l| l.iter().flat_map(|&(min, max)| [min, max]).collect::<Vec<_>>());
+    section(&mut out, "finger_load", finger_load.as_deref());
+    section(&mut out, "finger_load_penalty", penalty(cfg.finger_load_penalty).as_deref());
+    section(&mut out, "hand_balance", cfg.hand_balance.map(|v| vec![v]).as_deref());
+    section(&mut out, "hand_balance_penalty", penalty(cfg.hand_balance_penalty).as_deref());
+    out
 }
 
 pub fn load_histograms<P: AsRef<Path>>(
@@ -118,42 +513,334 @@ pub fn load_histograms<P: AsRef<Path>>(
     bigrams_path: P,
     trigrams_path: P,
 ) -> Result<Histograms> {
-    let mut unigrams: Vec<(Kc, f64)> = Vec::new();
-    for i in fs::read_to_string(unigrams_path)?.lines().skip(1) {
-        let items = i.split(char::is_whitespace).collect::<Vec<_>>();
-        if items.len() != 2 {
-            return Err(eyre!("weird unigrams line: {}", i));
+    let read = |p: P| -> Result<(PathBuf, String)> {
+        let p = p.as_ref();
+        Ok((p.to_owned(), fs::read_to_string(p)?))
+    };
+    let (path, text) = read(unigrams_path)?;
+    let unigrams = parse_grams::<1>(&path, &text, "unigrams")?;
+    let (path, text) = read(bigrams_path)?;
+    let bigrams = parse_grams::<2>(&path, &text, "bigrams")?;
+    let (path, text) = read(trigrams_path)?;
+    let trigrams = parse_grams::<3>(&path, &text, "trigrams")?;
+    Ok(Histograms {
+        unigrams: unigrams.into_iter().map(|([kc], v)| (kc, v)).collect(),
+        bigrams: bigrams.into_iter().map(|([kc1, kc2], v)| ((kc1, kc2), v)).collect(),
+        trigrams: trigrams.into_iter().map(|([kc1, kc2, kc3], v)| ((kc1, kc2, kc3), v)).collect(),
+    })
+}
+
+// Parses an n-gram data file of |kind|: a "<kind> <total>" header, or just the
+// total in older files, then one line per n-gram of N keys and a proportion.
+fn parse_grams<const N: usize>(path: &Path, text: &str, kind: &str) -> Result<Vec<([Kc; N], f64)>> {
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let Some((idx, header)) = lines.by_ref().find(|(_, l)| !is_comment(l)) else {
+        return Err(eyre!("{}: missing {} header", path.display(), kind));
+    };
+    let items: Vec<_> = header.split_whitespace().collect();
+    let (valid, legacy) = match items[..] {
+        [total] => (total.parse::<f64>().is_ok(), true),
+        [name, total] => (name == kind && total.parse::<f64>().is_ok(), false),
+        _ => (false, false),
+    };
+    if !valid {
+        let msg = format!("expected a \"{kind} <total>\" header");
+        return Err(ParseError::at_offset(path, idx, header, 0, msg).into());
+    }
+
+    let mut grams = Vec::new();
+    // Legacy files with just the total have no comments, and # is a key there.
+    for (idx, i) in lines.filter(|(_, l)| legacy || !is_comment(l)) {
+        let items: Vec<_> = i.split_whitespace().collect();
+        if items.len() != N + 1 {
+            let msg = format!("{kind} line must have {N} keys and a proportion");
+            return Err(ParseError::at_offset(path, idx, i, 0, msg).into());
+        }
+        let mut kcs = [Kc::None; N];
+        for (kc, s) in kcs.iter_mut().zip(&items) {
+            *kc = parse_at(path, idx, i, s, "key")?;
         }
-        let (kcstr, count) = (items[0], items[1].parse::<f64>()?);
-        let kc = Kc::from_str(kcstr)?;
-        unigrams.push((kc, count));
+        grams.push((kcs, parse_at(path, idx, i, items[N], "proportion")?));
     }
+    Ok(grams)
+}
 
-    let mut bigrams: Vec<((Kc, Kc), f64)> = Vec::new();
-    for i in fs::read_to_string(bigrams_path)?.lines().skip(1) {
-        let items = i.split(char::is_whitespace).collect::<Vec<_>>();
-        if items.len() != 3 {
-            return Err(eyre!("weird unigrams line: {}", i));
+// Lines starting with # are comments in config and data files, other than n-gram
+// files with a legacy header. A # key at the start of a line must be written as hash.
+fn is_comment(line: &str) -> bool {
+    line.trim_start().starts_with('#')
+}
+
+// Older files wrote a # key at the start of a row as is, which now reads as a
+// comment. Rather than silently drop such a row, reject comments which look like one.
+fn check_hash_row(path: &Path, idx: usize, line: &str) -> Result<(), ParseError> {
+    let mut tokens = line.split_whitespace();
+    let is_key = |t: &str| t == "X" || Kc::from_str(t).is_ok();
+    if tokens.next() == Some("#") && tokens.clone().next().is_some() && tokens.all(is_key) {
+        let msg = "row of keys starting with # is read as a comment, write the key as hash";
+        let offset = line.len() - line.trim_start().len();
+        return Err(ParseError::at_offset(path, idx, line, offset, msg.to_owned()));
+    }
+    Ok(())
+}
+
+// Name of |kc| in config and data files, which mustn't look like a comment.
+pub(crate) fn key_name(kc: Kc) -> String {
+    if kc == Kc::Hash { "hash".to_owned() } else { kc.to_string() }
+}
+
+// Writes one n-gram data file: the total count, then each n-gram and its proportion.
+fn write_grams<P: AsRef<Path>>(
+    p: P,
+    kind: &str,
+    total: f64,
+    mut grams: Vec<(String, f64)>,
+) -> Result<()> {
+    grams.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut out = format!("{kind} {total:.18}\n");
+    for (k, v) in grams {
+        writeln!(out, "{k} {v:.18}")?;
+    }
+    fs::write(p, out)?;
+    Ok(())
+}
+
+// Inverse of |load_histograms|. |totals| are written as the header of each file.
+pub fn write_histograms<P: AsRef<Path>>(
+    hist: &Histograms,
+    totals: [f64; 3],
+    unigrams_path: P,
+    bigrams_path: P,
+    trigrams_path: P,
+) -> Result<()> {
+    let unigrams = hist.unigrams.iter().map(|&(k, v)| (key_name(k), v)).collect();
+    write_grams(unigrams_path, "unigrams", totals[0], unigrams)?;
+    let bigrams = hist
+        .bigrams
+        .iter()
+        .map(|&((k1, k2), v)| (format!("{} {}", key_name(k1), key_name(k2)), v))
+        .collect();
+    write_grams(bigrams_path, "bigrams", totals[1], bigrams)?;
+    let trigrams = hist
+        .trigrams
+        .iter()
+        .map(|&((k1, k2, k3), v)| {
+            (format!("{} {} {}", key_name(k1), key_name(k2), key_name(k3)), v)
+        })
+        .collect();
+    write_grams(trigrams_path, "trigrams", totals[2], trigrams)?;
+    Ok(())
+}
+
+// Binary histogram format: magic, version, then the unigram, bigram and trigram
+// tables. Each table is its length followed by each n-gram's keycodes and
+// proportion. Numbers are little endian.
+const HIST_MAGIC: &[u8; 8] = b"MEMEHIST";
+pub(crate) const HIST_VERSION: u32 = 1;
+
+fn put_grams<const N: usize>(out: &mut Vec<u8>, grams: &[([Kc; N], f64)]) {
+    out.extend((grams.len() as u64).to_le_bytes());
+    for (keys, v) in grams {
+        out.extend(keys.map(|kc| i8::from(kc) as u8));
+        out.extend(v.to_le_bytes());
+    }
+}
+
+fn take<'a, const N: usize>(data: &mut &'a [u8]) -> Result<&'a [u8; N]> {
+    let (head, rest) = data.split_first_chunk().ok_or_else(|| eyre!("truncated histograms"))?;
+    *data = rest;
+    Ok(head)
+}
+
+fn get_grams<const N: usize>(data: &mut &[u8]) -> Result<Vec<([Kc; N], f64)>> {
+    let len = u64::from_le_bytes(*take(data)?);
+    let mut grams = Vec::new();
+    for _ in 0..len {
+        let mut keys = [Kc::None; N];
+        for (kc, &b) in keys.iter_mut().zip(take::<N>(data)?) {
+            *kc = Kc::try_from(b as i8).map_err(|_| eyre!("unknown keycode {}", b))?;
         }
-        let (kcstr1, kcstr2, count) = (items[0], items[1], items[2].parse::<f64>()?);
-        let kc1 = Kc::from_str(kcstr1)?;
-        let kc2 = Kc::from_str(kcstr2)?;
-        bigrams.push(((kc1, kc2), count));
+        grams.push((keys, f64::from_le_bytes(*take(data)?)));
+    }
+    Ok(grams)
+}
+
+#[must_use]
+pub fn encode_histograms(hist: &Histograms) -> Vec<u8> {
+    let mut out = HIST_MAGIC.to_vec();
+    out.extend(HIST_VERSION.to_le_bytes());
+    put_grams(&mut out, &hist.unigrams.iter().map(|&(k, v)| ([k], v)).collect::<Vec<_>>());
+    let bigrams: Vec<_> = hist.bigrams.iter().map(|&((k1, k2), v)| ([k1, k2], v)).collect();
+    put_grams(&mut out, &bigrams);
+    let trigrams: Vec<_> =
+        hist.trigrams.iter().map(|&((k1, k2, k3), v)| ([k1, k2, k3], v)).collect();
+    put_grams(&mut out, &trigrams);
+    out
+}
+
+pub fn decode_histograms(mut data: &[u8]) -> Result<Histograms> {
+    if take::<8>(&mut data)? != HIST_MAGIC {
+        return Err(eyre!("not a binary histogram file"));
+    }
+    let version = u32::from_le_bytes(*take(&mut data)?);
+    if version != HIST_VERSION {
+        return Err(eyre!("unsupported histogram version {}, expected {}", version, HIST_VERSION));
+    }
+    let unigrams = get_grams::<1>(&mut data)?.into_iter().map(|([k], v)| (k, v)).collect();
+    let bigrams = get_grams::<2>(&mut data)?.into_iter().map(|([k1, k2], v)| ((k1, k2), v));
+    let trigrams = get_grams::<3>(&mut data)?;
+    if !data.is_empty() {
+        return Err(eyre!("trailing data after histograms"));
+    }
+    Ok(Histograms {
+        unigrams,
+        bigrams: bigrams.collect(),
+        trigrams: trigrams.into_iter().map(|([k1, k2, k3], v)| ((k1, k2, k3), v)).collect(),
+    })
+}
+
+pub fn load_histograms_bin<P: AsRef<Path>>(p: P) -> Result<Histograms> {
+    let p = p.as_ref();
+    decode_histograms(&fs::read(p)?).wrap_err_with(|| eyre!("bad histograms {}", p.display()))
+}
+
+pub fn write_histograms_bin<P: AsRef<Path>>(hist: &Histograms, p: P) -> Result<()> {
+    fs::write(p, encode_histograms(hist))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn model_formats() {
+        let path = Path::new("layer1.cfg");
+        let cfg = load_model_cfg("cfg/layer1.cfg").unwrap();
+        let toml = toml::to_string_pretty(&cfg).unwrap();
+        assert_eq!(toml::from_str::<ModelCfg>(&toml).unwrap(), cfg);
+        let json = serde_json::to_string(&cfg).unwrap();
+        assert_eq!(serde_json::from_str::<ModelCfg>(&json).unwrap(), cfg);
+        assert_eq!(parse_legacy_model(&format_legacy_model(&cfg), path).unwrap(), cfg);
+        let e = parse_legacy_model("keys\na b\nrow\n1 x", path).unwrap_err();
+        let e = e.downcast::<ParseError>().unwrap();
+        assert_eq!((e.line, e.col), (4, 3));
+        let e = parse_legacy_model("fixed\nX None a\nX qq\n", path).unwrap_err();
+        let e = e.downcast::<ParseError>().unwrap();
+        assert_eq!((e.line, e.col), (3, 3));
+        assert!(e.msg.starts_with("bad fixed value \"qq\""), "{}", e.msg);
+        let e = parse_legacy_model("keys\na\n\nlayers\n1 2\n", path).unwrap_err();
+        let e = e.downcast::<ParseError>().unwrap();
+        assert_eq!((e.line, e.msg.as_str()), (4, "layers must be a single count"));
+        assert!(toml::from_str::<ModelCfg>("layuot = \"X\"").is_err());
     }
 
-    let mut trigrams: Vec<((Kc, Kc, Kc), f64)> = Vec::new();
-    for i in fs::read_to_string(trigrams_path)?.lines().skip(1) {
-        let items = i.split(char::is_whitespace).collect::<Vec<_>>();
-        if items.len() != 4 {
-            return Err(eyre!("weird unigrams line: {}", i));
+    #[test]
+    fn includes() {
+        let base = load_model_cfg("cfg/base.cfg").unwrap();
+        let layer0 = load_model_cfg("cfg/layer0.cfg").unwrap();
+        let moddh = load_model_cfg("cfg/layer0_moddh.cfg").unwrap();
+        assert_eq!(layer0.include, None);
+        assert_eq!(layer0.unigram_cost, base.unigram_cost);
+        assert_eq!(moddh.keys, layer0.keys);
+        assert_ne!(moddh.unigram_cost, layer0.unigram_cost);
+        assert!(moddh.build().is_ok());
+
+        let dir = std::env::temp_dir().join(format!("hodlr-includes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.toml"), "extends = \"b.cfg\"\n").unwrap();
+        fs::write(dir.join("b.cfg"), "include\na.toml\n").unwrap();
+        let e = load_model_cfg(dir.join("a.toml")).unwrap_err();
+        assert!(format!("{e:?}").contains("includes itself"), "{e:?}");
+
+        // Errors found when building point at the section, here in the including file.
+        let layer0 = fs::canonicalize("cfg/layer0.cfg").unwrap();
+        let legacy = format!("include\n{}\n\nrow\n0 1 2\n", layer0.display());
+        fs::write(dir.join("c.cfg"), legacy).unwrap();
+        let toml = format!("extends = {:?}\nrow = [0, 1, 2]\n", layer0.display().to_string());
+        fs::write(dir.join("c.toml"), toml).unwrap();
+        for (name, line) in [("c.cfg", 4), ("c.toml", 2)] {
+            let e = load_model(dir.join(name)).unwrap_err();
+            let e = e.downcast_ref::<ParseError>().unwrap();
+            assert_eq!(e.line, line);
+            assert_eq!(e.msg, "row has 3 values but there are 30 keys per layer");
         }
-        let (kcstr1, kcstr2, kcstr3, count) =
-            (items[0], items[1], items[2], items[3].parse::<f64>()?);
-        let kc1 = Kc::from_str(kcstr1)?;
-        let kc2 = Kc::from_str(kcstr2)?;
-        let kc3 = Kc::from_str(kcstr3)?;
-        trigrams.push(((kc1, kc2, kc3), count));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn seeds() {
+        // Layouts the tool prints read back, # keys included.
+        let model = load_model("cfg/layer1.cfg").unwrap();
+        let dir = std::env::temp_dir().join(format!("hodlr-seeds-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("layer1.layout");
+        fs::write(&path, model.format(&model.universe)).unwrap();
+        let seeds = load_seeds(&path, model.universe.len()).unwrap();
+        assert_eq!(seeds[0].0, model.universe);
+        let e = load_seeds(&path, 20).unwrap_err().downcast::<ParseError>().unwrap();
+        assert_eq!(e.msg, "layout has 30 keys, but the model has 20");
+
+        // Rows written with a leading # key before it meant a comment are rejected.
+        fs::write(&path, "# Layer 1.\n| a\n# \" (\n").unwrap();
+        let e = load_seeds(&path, 4).unwrap_err().downcast::<ParseError>().unwrap();
+        assert_eq!(e.line, 3);
+        let e = parse_legacy_model("fixed\n# X\n", &path).unwrap_err();
+        assert_eq!(e.downcast::<ParseError>().unwrap().line, 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn data_files() {
+        let path = Path::new("bigrams.data");
+        let text = "# Comment.\nbigrams 10\n\nhash a 0.5\n  # Another.\na # 0.5\n";
+        let grams = parse_grams::<2>(path, text, "bigrams").unwrap();
+        assert_eq!(grams, [([Kc::Hash, Kc::A], 0.5), ([Kc::A, Kc::Hash], 0.5)]);
+        assert_eq!(parse_grams::<2>(path, "10\na b 1.0\n", "bigrams").unwrap().len(), 1);
+        // Legacy files have no comments, so their # n-grams are kept.
+        let grams = parse_grams::<1>(Path::new("unigrams.data"), "10\n# 0.1\na 0.9\n", "unigrams");
+        assert_eq!(grams.unwrap(), [([Kc::Hash], 0.1), ([Kc::A], 0.9)]);
+        let grams = parse_grams::<2>(path, "10\n# ! 0.5\n\na # 0.5\n", "bigrams").unwrap();
+        assert_eq!(grams, [([Kc::Hash, Kc::Exclamation], 0.5), ([Kc::A, Kc::Hash], 0.5)]);
+        let e = parse_grams::<2>(path, "unigrams 10\n", "bigrams").unwrap_err();
+        assert!(e.to_string().contains("\"bigrams <total>\" header"), "{e}");
+        let e = parse_grams::<2>(path, "bigrams 10\na 1.0\n", "bigrams").unwrap_err();
+        let e = e.downcast::<ParseError>().unwrap();
+        assert_eq!((e.line, e.msg.as_str()), (2, "bigrams line must have 2 keys and a proportion"));
     }
 
-    Ok(Histograms { unigrams, bigrams, trigrams })
+    #[test]
+    fn binary_key_numbers() {
+        // Binary histograms store keys by number. If this changes, bump HIST_VERSION.
+        let names: Vec<_> =
+            (0..=i8::MAX).map_while(|i| Kc::try_from(i).ok()).map(|kc| format!("{kc:?}")).collect();
+        assert_eq!(
+            names.join(" "),
+            "None Num0 Num1 Num2 Num3 Num4 Num5 Num6 Num7 Num8 Num9 RightParen Exclamation AtSign \
+             Hash DollarSign PercentSign Caret Ampersand Asterisk LeftParen Minus Equals \
+             LeftBracket RightBracket Backslash Grave Quote Semicolon Comma Dot Slash Underscore \
+             Plus LeftBrace RightBrace Bar Tilde DoubleQuote Colon LeftAngle RightAngle \
+             QuestionMark A B C D E F G H I J K L M N O P Q R S T U V W X Y Z F1 F2 F3 F4 F5 F6 \
+             F7 F8 F9 F10 F11 F12 Space Enter Backspace Shift Ctrl Alt Gui Escape Tab Delete Left \
+             Down Up Right Home End PageUp PageDown Mo1 Mo2 Mo3 Mo4 Mo5 Mo6 Mo7"
+        );
+    }
+
+    #[test]
+    fn binary_histograms() {
+        let hist = Histograms {
+            unigrams: vec![(Kc::A, 0.75), (Kc::Shift, 0.25)],
+            bigrams: vec![((Kc::A, Kc::Shift), 1.0)],
+            trigrams: vec![((Kc::A, Kc::B, Kc::Dot), 1.0)],
+        };
+        let data = encode_histograms(&hist);
+        let decoded = decode_histograms(&data).unwrap();
+        assert_eq!(decoded.unigrams, hist.unigrams);
+        assert_eq!(decoded.bigrams, hist.bigrams);
+        assert_eq!(decoded.trigrams, hist.trigrams);
+        assert!(decode_histograms(&data[..data.len() - 1]).is_err());
+        assert!(decode_histograms(b"MEMEHIST\x02\0\0\0").is_err());
+    }
 }
@@ -35,11 +35,13 @@
     clippy::unreadable_literal
 )]
 
+use std::fs;
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use clap::Parser;
-use eyre::Result;
+use clap::{Parser, Subcommand};
+use eyre::{Result, WrapErr, eyre};
 use memega::eval::{CachedEvaluator, Evaluator};
 use memega::evolve::cfg::{
     Crossover, Duplicates, EvolveCfg, Mutation, Niching, Replacement, Species, Stagnation, Survival,
@@ -50,12 +52,24 @@ use memega::train::sampler::EmptyDataSampler;
 use memega::train::trainer::Trainer;
 use rand::prelude::SliceRandom;
 
-use crate::eval::{KeyState, LayoutEval};
-use crate::ingest::{load_model, load_seeds};
+use crate::corpus::{CorpusCfg, Counts};
+use crate::eval::{Histograms, KeyState, LayoutEval, PruneCfg};
+use crate::export::{qmk_keymap, qmk_template};
+use crate::ingest::{
+    load_histograms, load_histograms_bin, load_model, load_model_cfg, load_seeds, write_histograms,
+    write_histograms_bin, write_model_cfg,
+};
+use crate::types::Kc;
 
+pub mod cfg;
+pub mod corpus;
+pub mod error;
 pub mod eval;
+pub mod export;
+pub mod geometry;
 pub mod ingest;
 pub mod model;
+pub mod preset;
 pub mod types;
 
 #[must_use]
@@ -106,6 +120,14 @@ pub struct Args {
     )]
     pub trigrams_path: PathBuf,
 
+    #[clap(
+        long,
+        value_name = "FILE",
+        value_hint = clap::ValueHint::FilePath,
+        help = "Binary histogram file, used instead of the unigrams, bigrams and trigrams paths"
+    )]
+    pub histograms_path: Option<PathBuf>,
+
     #[clap(
         short,
         long,
@@ -114,29 +136,292 @@ pub struct Args {
         help = "Evaluate a given layout"
     )]
     pub eval_layout: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "PATH",
+        value_hint = clap::ValueHint::AnyPath,
+        help = "Files or directories of text to compute n-grams from, instead of the data files"
+    )]
+    pub corpus: Vec<PathBuf>,
+
+    #[clap(
+        long,
+        default_value = "data/cache",
+        value_name = "DIR",
+        value_hint = clap::ValueHint::DirPath,
+        help = "Directory to cache n-grams computed from a corpus in"
+    )]
+    pub cache_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub corpus_cfg: CorpusCfg,
+
+    #[clap(flatten)]
+    pub prune_cfg: PruneCfg,
+
+    #[clap(subcommand)]
+    pub cmd: Option<Cmd>,
+}
+
+#[must_use]
+#[derive(Debug, Subcommand)]
+pub enum Cmd {
+    #[clap(about = "Count n-grams in a corpus and write them to the unigrams, bigrams and \
+                    trigrams paths")]
+    Ngrams {
+        #[clap(
+            required = true,
+            value_name = "PATH",
+            value_hint = clap::ValueHint::AnyPath,
+            help = "Files or directories to read"
+        )]
+        paths: Vec<PathBuf>,
+    },
+    #[clap(about = "Count n-grams in keystroke logs and write them to the unigrams, bigrams and \
+                    trigrams paths")]
+    Keylog {
+        #[clap(
+            required = true,
+            value_name = "FILE",
+            value_hint = clap::ValueHint::FilePath,
+            help = "Logs with one evdev key event per line, e.g. KEY_A 1"
+        )]
+        paths: Vec<PathBuf>,
+    },
+    #[clap(about = "Convert the unigrams, bigrams and trigrams paths to a binary histogram file")]
+    Pack {
+        #[clap(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    #[clap(about = "Convert a binary histogram file to the unigrams, bigrams and trigrams paths")]
+    Unpack {
+        #[clap(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        path: PathBuf,
+    },
+    #[clap(about = "Convert a model config between the legacy, TOML and JSON formats, chosen by \
+                    extension")]
+    ConvertModel {
+        #[clap(value_name = "FROM", value_hint = clap::ValueHint::FilePath)]
+        from: PathBuf,
+        #[clap(value_name = "TO", value_hint = clap::ValueHint::FilePath)]
+        to: PathBuf,
+    },
+    #[clap(about = "Check the model and report keys which are in the histograms but not the \
+                    model, or in the model but never typed")]
+    Validate,
+    #[clap(about = "Mix weighted n-gram data sets and write them to the unigrams, bigrams and \
+                    trigrams paths")]
+    Combine {
+        #[clap(
+            required = true,
+            value_name = "WEIGHT:DIR",
+            help = "Weight and directory containing unigrams.data, bigrams.data and trigrams.data"
+        )]
+        sets: Vec<WeightedSet>,
+    },
+    #[clap(subcommand, about = "Export a layout for keyboard firmware")]
+    Export(Export),
+}
+
+#[must_use]
+#[derive(Debug, Subcommand)]
+pub enum Export {
+    #[clap(about = "Write the first layout in a file as a QMK keymap.c")]
+    Qmk {
+        #[clap(value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+        layout: PathBuf,
+        #[clap(
+            long,
+            value_name = "FILE",
+            value_hint = clap::ValueHint::FilePath,
+            help = "Arguments of the board's LAYOUT macro, with an X for each key of the model \
+                    layout. Defaults to the model layout"
+        )]
+        template: Option<PathBuf>,
+        #[clap(
+            short,
+            long,
+            value_name = "FILE",
+            value_hint = clap::ValueHint::FilePath,
+            help = "File to write the keymap to, instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+// A histogram data set and how much it contributes to a mix.
+#[must_use]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WeightedSet {
+    pub weight: f64,
+    pub dir: PathBuf,
+}
+
+impl FromStr for WeightedSet {
+    type Err = eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (weight, dir) =
+            s.split_once(':').ok_or_else(|| eyre!("expected WEIGHT:DIR, got {}", s))?;
+        let weight: f64 = weight.parse()?;
+        if !weight.is_finite() {
+            return Err(eyre!("weight in {s} must be finite"));
+        }
+        Ok(Self { weight, dir: dir.into() })
+    }
+}
+
+// Loads the histograms chosen by |args|, reporting how much pruning kept.
+pub fn histograms(args: &Args) -> Result<Histograms> {
+    let (hist, retained) = Histograms::from_args(args)?;
+    if let Some([uni, bi, tri]) = retained {
+        println!("pruned unigrams: {uni}\npruned bigrams: {bi}\npruned trigrams: {tri}");
+    }
+    Ok(hist)
 }
 
 pub fn eval_layout<P: AsRef<Path>>(p: P) -> Result<()> {
     let args = Args::parse();
-    let eval = LayoutEval::from_args(&args)?;
-    let l = load_seeds(p)?;
+    let eval = LayoutEval::new(load_model(&args.model_path)?, histograms(&args)?);
+    let l = load_seeds(p, eval.model.universe.len())?;
     let fitness = eval.fitness(&l[0], &())?;
+    let usage = eval.model.finger_usage(&l[0], &eval.hist.unigrams);
+    let hands = eval.model.hand_usage(&l[0], &eval.hist.unigrams);
     println!("layout:\n{}", eval.model.format(&l[0].0));
+    println!("{}", eval.cost(&l[0]));
+    println!("finger usage: {usage:.3?}");
+    println!("hand usage: {hands:.3?}");
+    let [typed, sent] = eval.model.shift_usage(&l[0], &eval.hist.unigrams);
+    println!("shifted symbols: {typed:.3} typed with shift, {sent:.3} shifted by the firmware");
+    if eval.model.layers > 1 {
+        let layers = eval.model.layer_usage(&l[0], &eval.hist.unigrams);
+        println!("layer usage: {layers:.3?}");
+    }
     println!("fitness: {fitness}");
     Ok(())
 }
 
+pub fn ngrams(args: &Args, paths: &[PathBuf]) -> Result<()> {
+    let counts = Counts::from_paths(paths, &args.corpus_cfg)?;
+    let totals = counts.totals();
+    println!("counted {} unigrams, {} bigrams, {} trigrams", totals[0], totals[1], totals[2]);
+    write_histograms(
+        &counts.histograms(),
+        totals,
+        &args.unigrams_path,
+        &args.bigrams_path,
+        &args.trigrams_path,
+    )
+}
+
+pub fn keylog(args: &Args, paths: &[PathBuf]) -> Result<()> {
+    let mut counts = Counts::default();
+    for p in paths {
+        let text =
+            fs::read_to_string(p).wrap_err_with(|| eyre!("can't read keylog {}", p.display()))?;
+        counts = counts.merge(Counts::from_keylog(p, &text)?);
+    }
+    let totals = counts.totals();
+    println!("counted {} unigrams, {} bigrams, {} trigrams", totals[0], totals[1], totals[2]);
+    write_histograms(
+        &counts.histograms(),
+        totals,
+        &args.unigrams_path,
+        &args.bigrams_path,
+        &args.trigrams_path,
+    )
+}
+
+pub fn combine(args: &Args, sets: &[WeightedSet]) -> Result<()> {
+    let mut weighted = Vec::new();
+    for set in sets {
+        let hist = load_histograms(
+            set.dir.join("unigrams.data"),
+            set.dir.join("bigrams.data"),
+            set.dir.join("trigrams.data"),
+        )?;
+        weighted.push((set.weight, hist));
+    }
+    write_histograms(
+        &Histograms::combine(&weighted)?,
+        [1.0; 3],
+        &args.unigrams_path,
+        &args.bigrams_path,
+        &args.trigrams_path,
+    )
+}
+
+pub fn validate(args: &Args) -> Result<()> {
+    let model = load_model(&args.model_path)?;
+    // Unpruned, so rare keys aren't reported as never typed.
+    let hist = Histograms::load(args)?;
+    let total: f64 = hist.unigrams.iter().map(|&(_, v)| v).sum();
+    let l = &model.universe;
+    let mut missing: Vec<_> =
+        hist.unigrams.iter().filter(|&&(kc, _)| model.find(l, kc).is_none()).copied().collect();
+    missing.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let untyped: Vec<_> = model
+        .universe
+        .iter()
+        .filter(|&&kc| kc != Kc::None && kc.mo_layer().is_none())
+        .filter(|&&kc| {
+            // Shifted symbols are typed with their unshifted key when pairing them.
+            let typed = |v: Kc| v == kc || (model.shift_pairs && v.unshifted() == Some(kc));
+            !hist.unigrams.iter().any(|&(v, _)| typed(v))
+        })
+        .collect();
+    println!("model {} has {} keys", args.model_path.display(), model.universe.len());
+    if !missing.is_empty() {
+        let mass: f64 = missing.iter().map(|&(_, v)| v).sum();
+        println!(
+            "{} keys not in the model, {:.3}% of key presses:",
+            missing.len(),
+            100.0 * mass / total
+        );
+        for (kc, v) in missing {
+            println!("  {kc} {:.3}%", 100.0 * v / total);
+        }
+    }
+    if !untyped.is_empty() {
+        let names: Vec<_> = untyped.iter().map(ToString::to_string).collect();
+        println!("{} keys never typed: {}", untyped.len(), names.join(" "));
+    }
+    Ok(())
+}
+
+pub fn export_qmk(
+    args: &Args,
+    layout: &Path,
+    template: Option<&Path>,
+    output: Option<&Path>,
+) -> Result<()> {
+    let model = load_model(&args.model_path)?;
+    let l = &load_seeds(layout, model.universe.len())?[0];
+    let template = match template {
+        Some(p) => fs::read_to_string(p)?,
+        None => qmk_template(&model),
+    };
+    let keymap = qmk_keymap(&model, l, &template)?;
+    match output {
+        Some(p) => fs::write(p, keymap)?,
+        None => print!("{keymap}"),
+    }
+    Ok(())
+}
+
 pub fn layout_evolver(cfg: EvolveCfg) -> Result<Evolver<impl Evaluator<Data = ()>>> {
     let args = Args::parse();
     let model = load_model(&args.model_path)?;
-    let eval = CachedEvaluator::new(LayoutEval::from_args(&args)?, 1000);
+    let eval = CachedEvaluator::new(LayoutEval::new(model.clone(), histograms(&args)?), 1000);
+    let size = model.universe.len();
     let genfn = move || {
         let mut keys = model.without_fixed(&model.universe);
         keys.shuffle(&mut rand::rng());
         KeyState(model.with_fixed(&keys))
     };
     if let Some(seed) = args.seed_path {
-        let initial_keys = load_seeds(seed)?;
+        let initial_keys = load_seeds(seed, size)?;
         Ok(Evolver::from_initial(eval, cfg, initial_keys, genfn))
     } else {
         Ok(Evolver::new(eval, cfg, genfn))
@@ -172,10 +457,34 @@ pub fn run() -> Result<()> {
         .set_par_fitness(true)
         .set_par_dist(true);
 
-    if let Some(p) = args.eval_layout {
-        eval_layout(p)?;
-    } else {
-        evolve(cfg)?;
+    match &args.cmd {
+        Some(Cmd::Ngrams { paths }) => ngrams(&args, paths)?,
+        Some(Cmd::Keylog { paths }) => keylog(&args, paths)?,
+        Some(Cmd::Pack { path }) => {
+            let hist =
+                load_histograms(&args.unigrams_path, &args.bigrams_path, &args.trigrams_path)?;
+            write_histograms_bin(&hist, path)?;
+        }
+        Some(Cmd::Unpack { path }) => write_histograms(
+            &load_histograms_bin(path)?,
+            [1.0; 3],
+            &args.unigrams_path,
+            &args.bigrams_path,
+            &args.trigrams_path,
+        )?,
+        Some(Cmd::ConvertModel { from, to }) => write_model_cfg(&load_model_cfg(from)?, to)?,
+        Some(Cmd::Combine { sets }) => combine(&args, sets)?,
+        Some(Cmd::Validate) => validate(&args)?,
+        Some(Cmd::Export(Export::Qmk { layout, template, output })) => {
+            export_qmk(&args, layout, template.as_deref(), output.as_deref())?;
+        }
+        None => {
+            if let Some(p) = args.eval_layout {
+                eval_layout(p)?;
+            } else {
+                evolve(cfg)?;
+            }
+        }
     }
 
     Ok(())
@@ -1,32 +1,348 @@
 use std::fmt::Write;
 
+use eyre::{Result, eyre};
+use serde::{Deserialize, Serialize};
+use strum::{Display as StrumDisplay, EnumString};
+
+use crate::error::SectionError;
+use crate::geometry::Geometry;
+use crate::ingest::key_name;
 use crate::types::Kc;
 
 const SWITCH_HAND: f64 = -0.5; // Alternating hands is easy.
 const SAME_KEY: f64 = 0.0; // Same key is neither easy nor hard.
 pub const PENALTY: f64 = 100.0;
+pub const THUMB: i32 = 4; // Finger value for thumbs.
+// Same hand thumb bigram costs: [thumb to thumb, finger to thumb, thumb to finger].
+pub const DEFAULT_THUMB_BIGRAM_COST: [f64; 3] = [1.5, 0.0, 0.0];
+pub const DEFAULT_SHIFT_SAME_HAND: f64 = 1.0;
+pub const DEFAULT_SHIFT_COST: f64 = 1.0;
+pub const DEFAULT_LAYER_COST: f64 = 0.5;
+pub const DEFAULT_LAYER_SWITCH_COST: f64 = 1.0;
+pub const DEFAULT_CHORD_COST: f64 = 1.0;
+pub const DEFAULT_LOAD_PENALTY: Penalty = Penalty { shape: PenaltyShape::Quadratic, weight: 100.0 };
+
+// Costs of same hand bigrams by first finger, second finger and row delta, from
+// the largest jump down to the largest jump up.
+#[must_use]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BigramTable {
+    pub fingers: usize,
+    pub max_jump: usize,
+    pub cost: Vec<f64>,
+}
+
+impl BigramTable {
+    // Works out the largest row delta from the number of costs given.
+    pub fn new(fingers: usize, cost: Vec<f64>) -> Result<Self> {
+        let per_jump = fingers * fingers;
+        if per_jump == 0 || cost.is_empty() || !cost.len().is_multiple_of(per_jump) {
+            return Err(eyre!(
+                "bigram table has {} values, which is not a multiple of {} fingers squared",
+                cost.len(),
+                fingers
+            ));
+        }
+        let deltas = cost.len() / per_jump;
+        if deltas.is_multiple_of(2) {
+            return Err(eyre!(
+                "bigram table has {} row deltas per finger pair, expected an odd number",
+                deltas
+            ));
+        }
+        Ok(Self { fingers, max_jump: deltas / 2, cost })
+    }
+
+    #[must_use]
+    pub fn get(&self, pfing: i32, cfing: i32, jump: i32) -> f64 {
+        let deltas = 2 * self.max_jump + 1;
+        let delta = (jump + self.max_jump as i32) as usize;
+        self.cost[(pfing as usize * self.fingers + cfing as usize) * deltas + delta]
+    }
+}
+
+impl<const F: usize, const D: usize> From<[[[f64; D]; F]; F]> for BigramTable {
+    fn from(v: [[[f64; D]; F]; F]) -> Self {
+        Self { fingers: F, max_jump: D / 2, cost: v.into_iter().flatten().flatten().collect() }
+    }
+}
+
+#[must_use]
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    Default,
+    Eq,
+    PartialEq,
+    Hash,
+    EnumString,
+    StrumDisplay,
+    Serialize,
+    Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum PenaltyShape {
+    #[default]
+    Linear,
+    Quadratic,
+}
+
+// Penalty for deviating from a target, e.g. a finger's workload.
+#[must_use]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Penalty {
+    pub shape: PenaltyShape,
+    pub weight: f64,
+}
+
+impl Penalty {
+    #[must_use]
+    pub fn cost(&self, deviation: f64) -> f64 {
+        match self.shape {
+            PenaltyShape::Linear => self.weight * deviation.abs(),
+            PenaltyShape::Quadratic => self.weight * deviation * deviation,
+        }
+    }
+}
 
 #[must_use]
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Model {
-    pub layout: String,    // Format string for printing keyboard layouts
-    pub universe: Vec<Kc>, // What keys we can use
-    pub fixed: Vec<Kc>,    // Positions of keys that should be fixed in place.
-    pub unigram_cost: Vec<f64>,
-    pub bigram_cost: [[[f64; 5]; 4]; 4],
+    pub layout: String,              // Format string for printing keyboard layouts
+    pub layers: usize,               // Layers sharing the physical keys. 0 is the same as 1.
+    pub layer_cost: f64,             // Extra cost of a key typed while holding a layer key.
+    pub layer_switch_cost: f64,      // Extra cost of a bigram which changes layer.
+    pub layer_capacity: Vec<usize>,  // Most keys each layer may hold, if not empty.
+    pub universe: Vec<Kc>,           // What keys we can use, each layer in turn, then each combo.
+    pub combos: Vec<(usize, usize)>, // Pairs of physical keys pressed together for a key.
+    pub chord_cost: f64,             // Extra cost of typing a combo.
+    pub fixed: Vec<Kc>,              // Positions of keys that should be fixed in place.
+    pub unigram_cost: Vec<f64>,      // This and the other per key values are per physical key.
+    pub bigram_cost: BigramTable,
+    pub hand_bigram_cost: [Option<BigramTable>; 2], // Per hand overrides of bigram_cost.
+    pub thumb_bigram_cost: [f64; 3],
+    pub shift_same_hand: f64, // Extra cost of a key typed with the same hand holding shift.
+    pub shift_pairs: bool,    // Shifted symbols are typed with shift and their unshifted key.
+    pub shift_cost: f64,      // Extra cost of a shifted symbol typed that way.
     pub row: Vec<i32>,
     pub hand: Vec<i32>,
     pub finger: Vec<i32>,
+    pub geometry: Option<Geometry>, // If present, bigram costs are derived from key positions.
+    pub finger_load: Vec<(f64, f64)>, // Allowed [min, max] proportion of key presses per finger.
+    pub finger_load_penalty: Penalty,
+    pub hand_balance: Option<f64>, // Target proportion of key presses on the left hand.
+    pub hand_balance_penalty: Penalty,
 }
 
 impl Model {
+    // Checks the invariants the cost functions and layout helpers rely on.
+    pub fn validate(&self) -> Result<()> {
+        let keys = self.positions();
+        let placeholders = self.layout.chars().filter(|&c| c == 'X').count();
+        if placeholders != keys {
+            let msg = format!(
+                "layout has {placeholders} X placeholders but there are {keys} keys per layer"
+            );
+            return Err(SectionError::report("layout", msg));
+        }
+        for &(a, b) in &self.combos {
+            if a.max(b) >= keys || a == b {
+                let msg = format!("combo {a} {b} must be two different keys of the layout");
+                return Err(SectionError::report("combos", msg));
+            }
+            if self.hand[a] == self.hand[b] && self.finger[a] == self.finger[b] {
+                let msg = format!("combo {a} {b} needs the same finger twice");
+                return Err(SectionError::report("combos", msg));
+            }
+        }
+        for layer in 1..self.layers {
+            let mo = Kc::mo(layer).ok_or_else(|| eyre!("too many layers"))?;
+            if !self.universe[..keys].contains(&mo) {
+                let msg = format!("layer {layer} needs a {mo} key in the first layer");
+                return Err(SectionError::report("keys", msg));
+            }
+        }
+        for (i, &kc) in self.universe.iter().enumerate() {
+            if kc != Kc::None && self.universe[..i].contains(&kc) {
+                let msg = format!("key {kc} appears more than once in keys");
+                return Err(SectionError::report("keys", msg));
+            }
+        }
+        for (i, &kc) in self.fixed.iter().enumerate() {
+            if kc == Kc::None || self.universe[i] == kc {
+                continue;
+            }
+            let msg = match self.universe.iter().position(|&v| v == kc) {
+                Some(j) => format!("fixed key {kc} is at position {i} but keys has it at {j}"),
+                None => format!("fixed key {kc} is not in keys"),
+            };
+            return Err(SectionError::report("fixed", msg));
+        }
+        if let Some((i, h)) = self.hand.iter().enumerate().find(|&(_, &h)| h != 0 && h != 1) {
+            let msg = format!("hand of key {i} is {h}, must be 0 (left) or 1 (right)");
+            return Err(SectionError::report("hand", msg));
+        }
+        if let Some((i, f)) =
+            self.finger.iter().enumerate().find(|&(_, &f)| !(0..=3).contains(&f) && f != THUMB)
+        {
+            let msg = format!("finger of key {i} is {f}, must be 0 to 3 or {THUMB} (thumb)");
+            return Err(SectionError::report("finger", msg));
+        }
+        Ok(())
+    }
+
+    // Number of physical keys, i.e. the number of keys in each layer.
+    #[must_use]
+    pub fn positions(&self) -> usize {
+        self.universe.len().saturating_sub(self.combos.len()) / self.layers.max(1)
+    }
+
+    // Combo typing |i|, an index into a layout, if it's one of the combos.
+    #[must_use]
+    pub fn combo(&self, i: usize) -> Option<usize> {
+        if self.combos.is_empty() {
+            return None;
+        }
+        i.checked_sub(self.universe.len() - self.combos.len())
+    }
+
+    // Physical key of |i|, an index into a layout. For a combo, its first key.
+    #[must_use]
+    pub fn pos(&self, i: usize) -> usize {
+        if let Some(c) = self.combo(i) {
+            self.combos[c].0
+        } else if self.layers > 1 {
+            i % self.positions()
+        } else {
+            i
+        }
+    }
+
+    // Physical keys pressed to type |i|, which are two for a combo.
+    #[must_use]
+    pub fn presses(&self, i: usize) -> [Option<usize>; 2] {
+        match self.combo(i) {
+            Some(c) => [Some(self.combos[c].0), Some(self.combos[c].1)],
+            None => [Some(self.pos(i)), None],
+        }
+    }
+
+    // Combos are typed on the first layer.
+    #[must_use]
+    pub fn layer(&self, i: usize) -> usize {
+        if self.layers > 1 && self.combo(i).is_none() { i / self.positions() } else { 0 }
+    }
+
+    // Extra cost of typing the key at |i| for holding its layer key, or None if
+    // it can't be typed because the layer key is missing or needs the same finger.
+    // Layer keys only work from the first layer.
+    #[must_use]
+    pub fn layer_reach(&self, l: &[Kc], i: usize) -> Option<f64> {
+        let layer = self.layer(i);
+        if layer == 0 {
+            return Some(0.0);
+        }
+        let mo = Kc::mo(layer)?;
+        let m = l[..self.positions()].iter().position(|&v| v == mo)?;
+        let p = self.pos(i);
+        if self.hand[m] == self.hand[p] && self.finger[m] == self.finger[p] {
+            return None;
+        }
+        Some(self.layer_cost)
+    }
+
+    // Cost of pressing the keys for |i|. A combo costs its harder key plus the chord cost.
+    #[must_use]
+    pub fn press_cost(&self, i: usize) -> f64 {
+        match self.combo(i) {
+            Some(c) => {
+                let (a, b) = self.combos[c];
+                self.unigram_cost[a].max(self.unigram_cost[b]) + self.chord_cost
+            }
+            None => self.unigram_cost[self.pos(i)],
+        }
+    }
+
+    // Index of |kc| in |l|, and whether it's typed with shift because only its
+    // unshifted key is placed, which needs |shift_pairs|.
+    #[must_use]
+    pub fn find(&self, l: &[Kc], kc: Kc) -> Option<(usize, bool)> {
+        if let Some(i) = l.iter().position(|&v| v == kc) {
+            return Some((i, false));
+        }
+        if !self.shift_pairs {
+            return None;
+        }
+        let base = kc.unshifted()?;
+        l.iter().position(|&v| v == base).map(|i| (i, true))
+    }
+
+    // Proportion of key presses of shifted symbols, split into those typed with
+    // shift and their unshifted key, and those placed directly, for which the
+    // firmware sends shift to the host.
+    #[must_use]
+    pub fn shift_usage(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> [f64; 2] {
+        let mut usage = [0.0; 2];
+        let mut total = 0.0;
+        for &(kc, prop) in unigrams {
+            let Some((_, shifted)) = self.find(l, kc) else { continue };
+            total += prop;
+            if shifted {
+                usage[0] += prop;
+            } else if kc.unshifted().is_some() {
+                usage[1] += prop;
+            }
+        }
+        if total > 0.0 {
+            usage = [usage[0] / total, usage[1] / total];
+        }
+        usage
+    }
+
+    // Penalty for each key beyond its layer's capacity.
+    #[must_use]
+    pub fn layer_capacity_cost(&self, l: &[Kc]) -> f64 {
+        let mut used = vec![0_usize; self.layer_capacity.len()];
+        for (i, &kc) in l.iter().enumerate() {
+            if kc != Kc::None
+                && let Some(n) = used.get_mut(self.layer(i))
+            {
+                *n += 1;
+            }
+        }
+        let over: usize =
+            used.iter().zip(&self.layer_capacity).map(|(&n, &cap)| n.saturating_sub(cap)).sum();
+        over as f64 * PENALTY
+    }
+
+    // Proportion of key presses typed on each layer.
+    #[must_use]
+    pub fn layer_usage(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> Vec<f64> {
+        let mut usage = vec![0.0; self.layers.max(1)];
+        for &(kc, prop) in unigrams {
+            if let Some((curi, _)) = self.find(l, kc) {
+                usage[self.layer(curi)] += prop;
+            }
+        }
+        let total: f64 = usage.iter().sum();
+        if total > 0.0 {
+            for v in &mut usage {
+                *v /= total;
+            }
+        }
+        usage
+    }
+
     #[must_use]
     pub fn unigram_cost(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> f64 {
         let mut cost = 0.0;
         for &(kc, prop) in unigrams {
             // Finger penalties - penalise for not being able to type characters.
-            let percost = if let Some(curi) = l.iter().position(|&v| v == kc) {
-                self.unigram_cost[curi]
+            let percost = if let Some((curi, shifted)) = self.find(l, kc) {
+                let shift = if shifted { self.shift_cost } else { 0.0 };
+                self.layer_reach(l, curi).map_or(PENALTY, |c| self.press_cost(curi) + c + shift)
             } else {
                 100.0
             };
@@ -35,13 +351,86 @@ impl Model {
         cost
     }
 
-    // Assumes key below is on the same hand and finger.
+    // Proportion of key presses typed by each finger, for keys in the layout.
+    #[must_use]
+    pub fn finger_usage(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> Vec<f64> {
+        let fingers = self.finger.iter().max().map_or(0, |&f| f as usize + 1);
+        let mut usage = vec![0.0; fingers];
+        for &(kc, prop) in unigrams {
+            // Each key of a combo is pressed.
+            if let Some((curi, _)) = self.find(l, kc) {
+                for p in self.presses(curi).into_iter().flatten() {
+                    usage[self.finger[p] as usize] += prop;
+                }
+            }
+        }
+        let total: f64 = usage.iter().sum();
+        if total > 0.0 {
+            for v in &mut usage {
+                *v /= total;
+            }
+        }
+        usage
+    }
+
+    #[must_use]
+    pub fn finger_load_cost(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> f64 {
+        if self.finger_load.is_empty() {
+            return 0.0;
+        }
+        let usage = self.finger_usage(l, unigrams);
+        let mut cost = 0.0;
+        for (&(min, max), &used) in self.finger_load.iter().zip(&usage) {
+            let deviation = if used < min {
+                min - used
+            } else if used > max {
+                used - max
+            } else {
+                0.0
+            };
+            cost += self.finger_load_penalty.cost(deviation);
+        }
+        cost
+    }
+
+    // Proportion of key presses typed by the left and right hands.
+    #[must_use]
+    pub fn hand_usage(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> [f64; 2] {
+        let mut usage = [0.0; 2];
+        for &(kc, prop) in unigrams {
+            if let Some((curi, _)) = self.find(l, kc) {
+                for p in self.presses(curi).into_iter().flatten() {
+                    usage[self.hand[p] as usize] += prop;
+                }
+            }
+        }
+        let total = usage[0] + usage[1];
+        if total > 0.0 {
+            usage = [usage[0] / total, usage[1] / total];
+        }
+        usage
+    }
+
+    #[must_use]
+    pub fn hand_balance_cost(&self, l: &[Kc], unigrams: &[(Kc, f64)]) -> f64 {
+        let Some(left) = self.hand_balance else {
+            return 0.0;
+        };
+        self.hand_balance_penalty.cost(self.hand_usage(l, unigrams)[0] - left)
+    }
+
+    // Assumes key below is on the same hand and finger, and the same layer.
     #[must_use]
     pub fn key_below(&self, v: usize) -> Option<usize> {
-        let row = self.row[v] - 1;
-        (0..self.row.len()).find(|&i| {
-            row == self.row[i] && self.hand[i] == self.hand[v] && self.finger[i] == self.finger[v]
-        })
+        if self.combo(v).is_some() {
+            return None;
+        }
+        let p = self.pos(v);
+        let row = self.row[p] - 1;
+        let below = (0..self.row.len()).find(|&i| {
+            row == self.row[i] && self.hand[i] == self.hand[p] && self.finger[i] == self.finger[p]
+        })?;
+        Some(v - p + below)
     }
 
     #[must_use]
@@ -49,49 +438,85 @@ impl Model {
         let mut cost = 0.0;
         for &((kc1, kc2), prop) in bigrams {
             // Model adapted from https://colemakmods.github.io/mod-dh/compare.html
-            let previ = l.iter().position(|&v| v == kc1);
-            let curi = l.iter().position(|&v| v == kc2);
+            let previ = self.find(l, kc1);
+            let curi = self.find(l, kc2);
             if previ.is_none() || curi.is_none() {
                 continue;
             }
-            let previ = previ.unwrap();
-            let curi = curi.unwrap();
-            let pfing = self.finger[previ] as usize;
-            let cfing = self.finger[curi] as usize;
-            let same_hand = self.hand[previ] == self.hand[curi];
-            let jump_len = (self.row[curi] - self.row[previ] + 2) as usize;
-
-            // Special case: same key incurs zero cost for bigrams.
-            // Index finger can be used twice on the same row with different keys.
-            let percost = if same_hand {
-                if kc1 == kc2 { SAME_KEY } else { self.bigram_cost[pfing][cfing][jump_len] }
+            let (previ, curi) = (previ.unwrap().0, curi.unwrap().0);
+            let same_key = previ == curi;
+            let (prevl, curl) = (self.layer(previ), self.layer(curi));
+            // Combos cost as much as the hardest pair of keys they move between.
+            let mut percost = f64::MIN;
+            for p in self.presses(previ).into_iter().flatten() {
+                for c in self.presses(curi).into_iter().flatten() {
+                    // A combo and one of its own keys share that key.
+                    percost = percost.max(self.key_bigram_cost(p, c, same_key || p == c));
+                }
+            }
+            let same_hand = self.hand[self.pos(previ)] == self.hand[self.pos(curi)];
+            let percost = if kc1 == Kc::Shift && same_hand && kc2 != Kc::Shift {
+                percost + self.shift_same_hand
             } else {
-                SWITCH_HAND
+                percost
             };
+            let percost = if prevl == curl { percost } else { percost + self.layer_switch_cost };
             cost += percost * prop;
         }
         cost
     }
 
+    // Cost of moving from physical key |previ| to |curi|.
+    #[must_use]
+    pub fn key_bigram_cost(&self, previ: usize, curi: usize, same_key: bool) -> f64 {
+        let pfing = self.finger[previ];
+        let cfing = self.finger[curi];
+        let same_hand = self.hand[previ] == self.hand[curi];
+        let jump = self.row[curi] - self.row[previ];
+
+        // Special case: same key incurs zero cost for bigrams.
+        // Index finger can be used twice on the same row with different keys.
+        // Thumbs mostly move sideways, so they have their own costs.
+        if same_hand {
+            if same_key {
+                SAME_KEY
+            } else if pfing == THUMB && cfing == THUMB {
+                self.thumb_bigram_cost[0]
+            } else if cfing == THUMB {
+                self.thumb_bigram_cost[1]
+            } else if pfing == THUMB {
+                self.thumb_bigram_cost[2]
+            } else if let Some(table) = &self.hand_bigram_cost[self.hand[curi] as usize] {
+                table.get(pfing, cfing, jump)
+            } else if let Some(geometry) = &self.geometry {
+                geometry.bigram_cost(previ, pfing, curi, cfing)
+            } else {
+                self.bigram_cost.get(pfing, cfing, jump)
+            }
+        } else {
+            SWITCH_HAND
+        }
+    }
+
     #[must_use]
     pub fn trigram_cost(&self, l: &[Kc], trigrams: &[((Kc, Kc, Kc), f64)]) -> f64 {
         const ALT_ROLL_BONUS: f64 = -1.0;
         let mut cost = 0.0;
         for &((kc1, kc2, kc3), prop) in trigrams {
             // Model adapted from https://colemakmods.github.io/mod-dh/compare.html
-            let i1 = l.iter().position(|&v| v == kc1);
-            let i2 = l.iter().position(|&v| v == kc2);
-            let i3 = l.iter().position(|&v| v == kc3);
+            let i1 = self.find(l, kc1).map(|(i, _)| i);
+            let i2 = self.find(l, kc2).map(|(i, _)| i);
+            let i3 = self.find(l, kc3).map(|(i, _)| i);
             if i1.is_none() || i2.is_none() || i3.is_none() {
                 continue;
             }
             // Bonus for rolling inward on one hand then swithing hand.
-            let i1 = i1.unwrap();
-            let i2 = i2.unwrap();
-            let i3 = i3.unwrap();
+            let i1 = self.pos(i1.unwrap());
+            let i2 = self.pos(i2.unwrap());
+            let i3 = self.pos(i3.unwrap());
 
             let alt = self.hand[i1] == self.hand[i2] && self.hand[i2] != self.hand[i3];
-            let rolling = self.finger[i1] > self.finger[i2];
+            let rolling = self.finger[i1] > self.finger[i2] && self.finger[i1] != THUMB;
             let percost = if alt && rolling { ALT_ROLL_BONUS } else { 0.0 };
             cost += percost * prop;
         }
@@ -101,15 +526,29 @@ impl Model {
     #[must_use]
     pub fn format(&self, l: &[Kc]) -> String {
         let mut s = String::new();
-        let mut idx = 0;
-        for c in self.layout.chars() {
-            if c == 'X' {
-                let _ = write!(s, "{}", l[idx]);
-                idx += 1;
-            } else {
-                s.push(c);
+        // Each layer is printed in turn, separated by a blank line.
+        let per_layer = self.layout.matches('X').count().max(1);
+        let (l, combos) = l.split_at(l.len().saturating_sub(self.combos.len()));
+        for (layer, keys) in l.chunks(per_layer).enumerate() {
+            if layer > 0 {
+                s.truncate(s.trim_end().len());
+                s += "\n\n";
+            }
+            let mut keys = keys.iter();
+            for c in self.layout.chars() {
+                if c != 'X' {
+                    s.push(c);
+                } else if let Some(&kc) = keys.next() {
+                    s += &key_name(kc);
+                }
             }
         }
+        // Then each combo, as the first layer keys it's typed with.
+        for (c, (&(a, b), kc)) in self.combos.iter().zip(combos).enumerate() {
+            s.truncate(s.trim_end().len());
+            s += if c == 0 { "\n\n" } else { "\n" };
+            let _ = write!(s, "{} + {} = {}", key_name(l[a]), key_name(l[b]), key_name(*kc));
+        }
         s.truncate(s.trim_end().len());
         s
     }
@@ -172,6 +611,30 @@ mod tests {
         assert_eq!(model.key_below(11), None);
     }
 
+    #[test]
+    fn validate() {
+        let model = Model {
+            layout: "X X\nX".to_owned(),
+            universe: vec![Kc::A, Kc::B, Kc::C],
+            fixed: vec![Kc::None, Kc::B, Kc::None],
+            hand: vec![0, 0, 1],
+            finger: vec![0, 3, THUMB],
+            ..Default::default()
+        };
+        assert!(model.validate().is_ok());
+        let bad = [
+            Model { layout: "X X".to_owned(), ..model.clone() },
+            Model { universe: vec![Kc::A, Kc::B, Kc::A], ..model.clone() },
+            Model { fixed: vec![Kc::None, Kc::D, Kc::None], ..model.clone() },
+            Model { fixed: vec![Kc::None, Kc::None, Kc::A], ..model.clone() },
+            Model { hand: vec![0, 2, 1], ..model.clone() },
+            Model { finger: vec![0, 5, 1], ..model.clone() },
+        ];
+        for m in bad {
+            assert!(m.validate().is_err(), "{m:?}");
+        }
+    }
+
     #[test]
     fn unigrams() {
         let model = Model { unigram_cost: vec![1.0, 10.0], ..Default::default() };
@@ -183,7 +646,7 @@ mod tests {
     #[test]
     fn bigrams() {
         let model = Model {
-            bigram_cost: [
+            bigram_cost: BigramTable::from([
                 [
                     // First finger: index - [down 2, down 1, same row, up 1, up 2]
                     [0.0, 0.0, 2.5, 3.0, 4.0], // Index - same row val only used for different key locations
@@ -212,7 +675,7 @@ mod tests {
                     [0.0, 0.0, -1.0, 0.0, 1.5], // Ring - inward roll
                     [0.0, 0.0, 3.0, 4.0, 5.5], // Pinkie - same row val only used for different key locations
                 ],
-            ],
+            ]),
             unigram_cost: vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0],
             row: vec![2, 2, 2, 2, 1, 1, 1, 1, 0, 0, 0, 0],
             hand: vec![0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 1, 1],
@@ -260,4 +723,193 @@ mod tests {
         assert_relative_eq!(-1.0, model.trigram_cost(l, &[((Kc::A, Kc::B, Kc::C), 1.0)]));
         assert_relative_eq!(0.0, model.trigram_cost(l, &[((Kc::B, Kc::A, Kc::C), 1.0)]));
     }
+
+    #[test]
+    fn thumbs() {
+        let model = Model {
+            thumb_bigram_cost: [1.5, 0.25, 0.5],
+            row: vec![1, 1, 0, 0, 1],
+            hand: vec![0, 0, 0, 0, 1],
+            finger: vec![1, 0, THUMB, THUMB, 0],
+            ..Default::default()
+        };
+        let l = &[Kc::A, Kc::B, Kc::Space, Kc::Enter, Kc::C];
+        assert_relative_eq!(1.5, model.bigram_cost(l, &[((Kc::Space, Kc::Enter), 1.0)]));
+        assert_relative_eq!(0.25, model.bigram_cost(l, &[((Kc::A, Kc::Space), 1.0)]));
+        assert_relative_eq!(0.5, model.bigram_cost(l, &[((Kc::Enter, Kc::B), 1.0)]));
+        // Thumbs don't roll.
+        assert_relative_eq!(0.0, model.trigram_cost(l, &[((Kc::Space, Kc::A, Kc::C), 1.0)]));
+    }
+
+    #[test]
+    fn shift() {
+        let model = Model {
+            bigram_cost: BigramTable::from([[[0.0]; 2]; 2]),
+            shift_same_hand: 2.0,
+            row: vec![0, 0, 0],
+            hand: vec![0, 0, 1],
+            finger: vec![1, 0, 0],
+            ..Default::default()
+        };
+        let l = &[Kc::Shift, Kc::A, Kc::B];
+        assert_relative_eq!(2.0, model.bigram_cost(l, &[((Kc::Shift, Kc::A), 1.0)]));
+        assert_relative_eq!(SWITCH_HAND, model.bigram_cost(l, &[((Kc::Shift, Kc::B), 1.0)]));
+        // Only holding shift is penalised, not reaching for it.
+        assert_relative_eq!(0.0, model.bigram_cost(l, &[((Kc::A, Kc::Shift), 1.0)]));
+    }
+
+    #[test]
+    fn layers() {
+        let model = Model {
+            layout: "X X X".to_owned(),
+            layers: 2,
+            layer_cost: 0.5,
+            layer_switch_cost: 1.0,
+            universe: vec![Kc::A, Kc::Mo1, Kc::B, Kc::C, Kc::None, Kc::D],
+            fixed: vec![Kc::None; 6],
+            unigram_cost: vec![1.0, 2.0, 3.0],
+            bigram_cost: BigramTable::from([[[0.0]; 2]; 2]),
+            row: vec![0, 0, 0],
+            hand: vec![0, 0, 1],
+            finger: vec![0, 1, 0],
+            ..Default::default()
+        };
+        assert!(model.validate().is_ok());
+        let l = &model.universe;
+        assert_eq!(model.format(l), "a mo1 b\n\nc None d");
+        assert_eq!((model.pos(5), model.layer(5)), (2, 1));
+        assert_relative_eq!(1.5, model.unigram_cost(l, &[(Kc::C, 1.0)]));
+        assert_relative_eq!(3.5, model.unigram_cost(l, &[(Kc::D, 1.0)]));
+        // C and A are on the same key, so switching layer is like a same finger bigram.
+        assert_relative_eq!(1.0, model.bigram_cost(l, &[((Kc::A, Kc::C), 1.0)]));
+        assert_relative_eq!(SWITCH_HAND, model.bigram_cost(l, &[((Kc::C, Kc::D), 1.0)]));
+        // Holding the layer key with the finger which types C makes C unreachable.
+        let l = &[Kc::Mo1, Kc::A, Kc::B, Kc::C, Kc::None, Kc::D];
+        assert_relative_eq!(PENALTY, model.unigram_cost(l, &[(Kc::C, 1.0)]));
+
+        let usage = model.layer_usage(l, &[(Kc::A, 0.75), (Kc::C, 0.25)]);
+        assert_relative_eq!(0.25, usage[1]);
+        let full = Model { layer_capacity: vec![3, 1], ..model.clone() };
+        assert_relative_eq!(PENALTY, full.layer_capacity_cost(l));
+
+        let universe = vec![Kc::A, Kc::E, Kc::B, Kc::C, Kc::None, Kc::D];
+        assert!(Model { universe, ..model.clone() }.validate().is_err());
+        assert!(Model { layers: 3, ..model }.validate().is_err());
+    }
+
+    #[test]
+    fn shift_pairs() {
+        let model = Model {
+            bigram_cost: BigramTable::from([[[0.0]; 2]; 2]),
+            shift_pairs: true,
+            shift_cost: 0.5,
+            unigram_cost: vec![1.0, 2.0, 3.0],
+            row: vec![0, 0, 0],
+            hand: vec![0, 0, 1],
+            finger: vec![1, 0, 0],
+            ..Default::default()
+        };
+        let l = &[Kc::Num9, Kc::Num0, Kc::DollarSign];
+        assert_eq!(model.find(l, Kc::LeftParen), Some((0, true)));
+        assert_relative_eq!(1.5, model.unigram_cost(l, &[(Kc::LeftParen, 1.0)]));
+        // ( and 9 are the same key.
+        assert_relative_eq!(SAME_KEY, model.bigram_cost(l, &[((Kc::Num9, Kc::LeftParen), 1.0)]));
+        let unigrams = &[(Kc::LeftParen, 0.25), (Kc::DollarSign, 0.25), (Kc::Num0, 0.5)];
+        let [typed, sent] = model.shift_usage(l, unigrams);
+        assert_relative_eq!(0.25, typed);
+        assert_relative_eq!(0.25, sent);
+        let custom = Model { shift_pairs: false, ..model };
+        assert_eq!(custom.find(l, Kc::LeftParen), None);
+    }
+
+    #[test]
+    fn combos() {
+        let model = Model {
+            layout: "X X X".to_owned(),
+            universe: vec![Kc::A, Kc::B, Kc::C, Kc::D],
+            fixed: vec![Kc::None; 4],
+            combos: vec![(0, 1)],
+            chord_cost: 1.0,
+            unigram_cost: vec![1.0, 2.0, 3.0],
+            bigram_cost: BigramTable::from([[[0.0], [0.5]], [[1.5], [0.0]]]),
+            row: vec![0, 0, 0],
+            hand: vec![0, 0, 1],
+            finger: vec![1, 0, 0],
+            ..Default::default()
+        };
+        assert!(model.validate().is_ok());
+        let l = &model.universe;
+        assert_eq!(model.format(l), "a b c\n\na + b = d");
+        assert_relative_eq!(3.0, model.unigram_cost(l, &[(Kc::D, 1.0)]));
+        // The combo costs as much as its worst key to move to.
+        assert_relative_eq!(1.5, model.bigram_cost(l, &[((Kc::A, Kc::D), 1.0)]));
+        assert_relative_eq!(SWITCH_HAND, model.bigram_cost(l, &[((Kc::C, Kc::D), 1.0)]));
+        // Moving to a combo from one of its keys presses that key again.
+        let table = BigramTable::from([[[2.0], [0.5]], [[1.5], [2.0]]]);
+        let heavy = Model { bigram_cost: table, ..model.clone() };
+        assert_relative_eq!(0.5, heavy.bigram_cost(l, &[((Kc::B, Kc::D), 1.0)]));
+        // Both keys of a combo count towards finger and hand usage.
+        let unigrams = &[(Kc::C, 0.5), (Kc::D, 0.5)];
+        let [left, right] = model.hand_usage(l, unigrams);
+        assert_relative_eq!(2.0 / 3.0, left);
+        assert_relative_eq!(1.0 / 3.0, right);
+        let fingers = model.finger_usage(l, unigrams);
+        assert_relative_eq!(2.0 / 3.0, fingers[0]);
+        assert_relative_eq!(1.0 / 3.0, fingers[1]);
+        assert_eq!(model.key_below(3), None);
+
+        assert!(Model { combos: vec![(0, 0)], ..model.clone() }.validate().is_err());
+        assert!(Model { combos: vec![(0, 3)], ..model }.validate().is_err());
+    }
+
+    #[test]
+    fn finger_load() {
+        let model = Model {
+            finger: vec![0, 0, 1, 3],
+            finger_load: vec![(0.3, 1.0), (0.0, 1.0), (0.0, 1.0), (0.0, 0.08)],
+            finger_load_penalty: Penalty { shape: PenaltyShape::Linear, weight: 10.0 },
+            ..Default::default()
+        };
+        let l = &[Kc::A, Kc::B, Kc::C, Kc::D];
+        let unigrams = &[(Kc::A, 0.1), (Kc::B, 0.1), (Kc::C, 0.6), (Kc::D, 0.2)];
+        let usage = model.finger_usage(l, unigrams);
+        assert_relative_eq!(0.2, usage[0]);
+        assert_relative_eq!(0.0, usage[2]);
+        // Index is 0.1 under its minimum and pinkie is 0.12 over its maximum.
+        assert_relative_eq!(2.2, model.finger_load_cost(l, unigrams));
+        let model = Model {
+            finger_load_penalty: Penalty { shape: PenaltyShape::Quadratic, weight: 10.0 },
+            ..model
+        };
+        assert_relative_eq!(0.244, model.finger_load_cost(l, unigrams));
+    }
+
+    #[test]
+    fn hand_balance() {
+        let model = Model {
+            hand: vec![0, 0, 1, 1],
+            hand_balance: Some(0.5),
+            hand_balance_penalty: Penalty { shape: PenaltyShape::Linear, weight: 10.0 },
+            ..Default::default()
+        };
+        let l = &[Kc::A, Kc::B, Kc::C, Kc::D];
+        let unigrams = &[(Kc::A, 0.3), (Kc::B, 0.4), (Kc::C, 0.2), (Kc::D, 0.1)];
+        assert_relative_eq!(0.7, model.hand_usage(l, unigrams)[0]);
+        assert_relative_eq!(2.0, model.hand_balance_cost(l, unigrams));
+        assert_relative_eq!(0.0, model.hand_balance_cost(l, &[(Kc::A, 0.5), (Kc::D, 0.5)]));
+    }
+
+    #[test]
+    fn bigram_table() {
+        // Two fingers on a four row board, so row deltas go from -3 to 3.
+        let cost: Vec<f64> = (0..28).map(f64::from).collect();
+        let table = BigramTable::new(2, cost.clone()).unwrap();
+        assert_eq!(table.max_jump, 3);
+        assert_relative_eq!(0.0, table.get(0, 0, -3));
+        assert_relative_eq!(3.0, table.get(0, 0, 0));
+        assert_relative_eq!(13.0, table.get(0, 1, 3));
+        assert_relative_eq!(21.0, table.get(1, 1, -3));
+        assert!(BigramTable::new(2, cost[..27].to_vec()).is_err());
+        assert!(BigramTable::new(2, cost[..24].to_vec()).is_err());
+    }
 }
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use strum::{Display as StrumDisplay, EnumString};
+
+use crate::cfg::ModelCfg;
+use crate::geometry::KEY_UNIT;
+use crate::model::THUMB;
+
+// Built in keyboard geometries. Each provides the layout, positions, fingers
+// and a default effort grid, so a model only needs to give its keys.
+#[must_use]
+#[derive(
+    Debug,
+    Copy,
+    Clone,
+    Ord,
+    PartialOrd,
+    Eq,
+    PartialEq,
+    Hash,
+    EnumString,
+    StrumDisplay,
+    Serialize,
+    Deserialize,
+)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    Ansi,    // 3x10 main block of a row-staggered keyboard.
+    Ortho,   // 3x10 ortholinear.
+    Corne,   // Split 3x6+3 column-staggered.
+    Ergodox, // Split 3x7+3 column-staggered.
+}
+
+// Description of one hand of a board, from the outermost column inwards.
+struct Board {
+    finger: &'static [i32],
+    home: &'static [bool],
+    row_stagger: [f64; 3], // Horizontal offset of each row from the top, in key units.
+    col_stagger: &'static [f64], // Vertical offset of each column, in key units.
+    effort: [&'static [f64]; 3], // Effort grid of each row from the top.
+    thumb_col: &'static [f64], // Column of each thumb key, in key units.
+    thumb_effort: &'static [f64],
+    thumb_home: usize,
+    split: bool,
+}
+
+const ANSI: Board = Board {
+    finger: &[3, 2, 1, 0, 0],
+    home: &[true, true, true, true, false],
+    row_stagger: [0.0, 0.25, 0.75],
+    col_stagger: &[0.0, 0.0, 0.0, 0.0, 0.0],
+    effort: [&[4.5, 2.4, 2.0, 2.2, 3.2], &[3.0, 1.3, 1.1, 1.0, 2.9], &[4.0, 2.6, 2.3, 1.6, 3.0]],
+    thumb_col: &[],
+    thumb_effort: &[],
+    thumb_home: 0,
+    split: false,
+};
+
+const ORTHO: Board = Board {
+    finger: &[3, 2, 1, 0, 0],
+    home: &[true, true, true, true, false],
+    row_stagger: [0.0, 0.0, 0.0],
+    col_stagger: &[0.0, 0.0, 0.0, 0.0, 0.0],
+    effort: [&[3.0, 2.4, 2.0, 2.2, 3.2], &[1.6, 1.3, 1.1, 1.0, 2.9], &[3.2, 2.6, 2.3, 1.6, 3.0]],
+    thumb_col: &[],
+    thumb_effort: &[],
+    thumb_home: 0,
+    split: false,
+};
+
+const CORNE: Board = Board {
+    finger: &[3, 3, 2, 1, 0, 0],
+    home: &[false, true, true, true, true, false],
+    row_stagger: [0.0, 0.0, 0.0],
+    col_stagger: &[0.0, 0.0, 0.25, 0.375, 0.25, 0.125],
+    effort: [
+        &[4.5, 3.0, 2.4, 2.0, 2.2, 3.2],
+        &[3.5, 1.6, 1.3, 1.1, 1.0, 2.9],
+        &[4.5, 3.2, 2.6, 2.3, 1.6, 3.0],
+    ],
+    thumb_col: &[3.5, 4.5, 5.5],
+    thumb_effort: &[1.8, 1.0, 1.4],
+    thumb_home: 1,
+    split: true,
+};
+
+const ERGODOX: Board = Board {
+    finger: &[3, 3, 2, 1, 0, 0, 0],
+    home: &[false, true, true, true, true, false, false],
+    row_stagger: [0.0, 0.0, 0.0],
+    col_stagger: &[0.0, 0.0, 0.125, 0.25, 0.125, 0.0, 0.0],
+    effort: [
+        &[4.5, 3.0, 2.4, 2.0, 2.2, 3.2, 4.0],
+        &[3.5, 1.6, 1.3, 1.1, 1.0, 2.9, 3.8],
+        &[4.5, 3.2, 2.6, 2.3, 1.6, 3.0, 4.0],
+    ],
+    thumb_col: &[4.5, 5.5, 6.5],
+    thumb_effort: &[1.4, 1.0, 1.8],
+    thumb_home: 1,
+    split: true,
+};
+
+impl Preset {
+    fn board(self) -> &'static Board {
+        match self {
+            Preset::Ansi => &ANSI,
+            Preset::Ortho => &ORTHO,
+            Preset::Corne => &CORNE,
+            Preset::Ergodox => &ERGODOX,
+        }
+    }
+
+    pub fn cfg(self) -> ModelCfg {
+        let board = self.board();
+        let cols = board.finger.len();
+        let mut layout = String::new();
+        let (mut unigram_cost, mut row, mut hand, mut finger) = (vec![], vec![], vec![], vec![]);
+        let (mut pos_x, mut pos_y, mut home) = (vec![], vec![], vec![]);
+        for r in 0..3 {
+            for c in 0..2 * cols {
+                // Right hand columns mirror the left hand.
+                let (h, hc) = if c < cols { (0, c) } else { (1, 2 * cols - 1 - c) };
+                let gap = if h == 1 && board.split { 2.0 } else { 0.0 };
+                if c > 0 {
+                    layout += if c == cols && board.split { "   " } else { " " };
+                }
+                layout.push('X');
+                unigram_cost.push(board.effort[r][hc]);
+                row.push(2 - r as i32);
+                hand.push(h);
+                finger.push(board.finger[hc]);
+                pos_x.push((c as f64 + gap + board.row_stagger[r]) * KEY_UNIT);
+                pos_y.push(((2 - r) as f64 + board.col_stagger[hc]) * KEY_UNIT);
+                home.push(r == 1 && board.home[hc]);
+            }
+            layout.push('\n');
+        }
+        // Thumb keys sit below the bottom row, left hand outer to inner then the right hand
+        // mirrored.
+        let thumbs = board.thumb_col.len();
+        if thumbs > 0 {
+            let mut line = vec![' '; 4 * cols + 2];
+            for t in 0..2 * thumbs {
+                let (h, ht) = if t < thumbs { (0, t) } else { (1, 2 * thumbs - 1 - t) };
+                let col = board.thumb_col[ht];
+                let (x, screen) = if h == 0 {
+                    (col, 2 * col.floor() as usize)
+                } else {
+                    let mirrored = (2 * cols) as f64 - 1.0 - col;
+                    (mirrored + 2.0, 2 * mirrored.ceil() as usize + 2)
+                };
+                line[screen] = 'X';
+                unigram_cost.push(board.thumb_effort[ht]);
+                row.push(-1);
+                hand.push(h);
+                finger.push(THUMB);
+                pos_x.push(x * KEY_UNIT);
+                pos_y.push(-KEY_UNIT);
+                home.push(ht == board.thumb_home);
+            }
+            layout += line.into_iter().collect::<String>().trim_end();
+            layout.push('\n');
+        }
+        ModelCfg {
+            layout: Some(layout),
+            unigram_cost: Some(unigram_cost),
+            row: Some(row),
+            hand: Some(hand),
+            finger: Some(finger),
+            pos_x: Some(pos_x),
+            pos_y: Some(pos_y),
+            home: Some(home),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::types::{Kc, QWERTY};
+
+    #[test]
+    fn presets() {
+        for (preset, cols, size) in [
+            (Preset::Ansi, 5, 30),
+            (Preset::Ortho, 5, 30),
+            (Preset::Corne, 6, 42),
+            (Preset::Ergodox, 7, 48),
+        ] {
+            let mut keys = QWERTY.to_vec();
+            keys.resize(size, Kc::None);
+            let cfg = ModelCfg { keys: Some(keys), ..Default::default() };
+            let model = preset.cfg().merge(cfg).build().unwrap();
+            assert_eq!(model.universe.len(), size);
+            assert_eq!(model.layout.matches('X').count(), size);
+            assert!(model.geometry.is_some());
+            // Fingers on the right hand mirror the left.
+            assert_eq!(model.finger[0], model.finger[2 * cols - 1]);
+            assert_eq!(model.hand[2 * cols - 1], 1);
+            // Thumbs come after the main block.
+            assert!(model.finger[6 * cols..].iter().all(|&f| f == THUMB));
+        }
+    }
+}
@@ -1,7 +1,11 @@
-use num_enum::IntoPrimitive;
+use std::str::FromStr;
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use strum::{Display as StrumDisplay, EnumString};
 
-// Based on QMK keycodes.
+// Based on QMK keycodes. Binary histogram files store keys by number, so only
+// append new variants, and bump HIST_VERSION if the numbers ever change.
 #[must_use]
 #[derive(
     Debug,
@@ -15,6 +19,7 @@ use strum::{Display as StrumDisplay, EnumString};
     Hash,
     StrumDisplay,
     IntoPrimitive,
+    TryFromPrimitive,
 )]
 #[repr(i8)]
 #[derive(Default)]
@@ -51,7 +56,7 @@ pub enum Kc {
     Exclamation,
     #[strum(serialize = "@")]
     AtSign,
-    #[strum(serialize = "#")]
+    #[strum(to_string = "#", serialize = "hash")]
     Hash,
     #[strum(serialize = "$")]
     DollarSign,
@@ -181,6 +186,275 @@ pub enum Kc {
     F10,
     F11,
     F12,
+
+    // Thumb keys
+    #[strum(serialize = "spc")]
+    Space,
+    #[strum(serialize = "ent")]
+    Enter,
+    #[strum(serialize = "bspc")]
+    Backspace,
+
+    // Modifiers
+    #[strum(serialize = "sft")]
+    Shift,
+    #[strum(serialize = "ctl")]
+    Ctrl,
+    #[strum(serialize = "alt")]
+    Alt,
+    #[strum(serialize = "gui")]
+    Gui,
+
+    // Navigation and editing
+    #[strum(serialize = "esc")]
+    Escape,
+    #[strum(serialize = "tab")]
+    Tab,
+    #[strum(serialize = "del")]
+    Delete,
+    #[strum(serialize = "left")]
+    Left,
+    #[strum(serialize = "down")]
+    Down,
+    #[strum(serialize = "up")]
+    Up,
+    #[strum(serialize = "right")]
+    Right,
+    #[strum(serialize = "home")]
+    Home,
+    #[strum(serialize = "end")]
+    End,
+    #[strum(serialize = "pgup")]
+    PageUp,
+    #[strum(serialize = "pgdn")]
+    PageDown,
+
+    // Momentary layer keys, held to type keys on that layer.
+    #[strum(serialize = "mo1")]
+    Mo1,
+    #[strum(serialize = "mo2")]
+    Mo2,
+    #[strum(serialize = "mo3")]
+    Mo3,
+    #[strum(serialize = "mo4")]
+    Mo4,
+    #[strum(serialize = "mo5")]
+    Mo5,
+    #[strum(serialize = "mo6")]
+    Mo6,
+    #[strum(serialize = "mo7")]
+    Mo7,
+}
+
+pub const MAX_LAYERS: usize = 8; // Layer 0 plus one for each momentary layer key.
+
+impl Kc {
+    // Momentary layer key for |layer|, if there is one.
+    #[must_use]
+    pub fn mo(layer: usize) -> Option<Kc> {
+        if layer == 0 || layer >= MAX_LAYERS {
+            return None;
+        }
+        Kc::try_from(i8::from(Kc::Mo1) + layer as i8 - 1).ok()
+    }
+
+    // Layer held by this key, if it's a momentary layer key.
+    #[must_use]
+    pub fn mo_layer(self) -> Option<usize> {
+        (1..MAX_LAYERS).find(|&layer| Kc::mo(layer) == Some(self))
+    }
+
+    // Key with the given Linux evdev name, e.g. KEY_A or KEY_LEFTSHIFT.
+    #[must_use]
+    pub fn from_evdev(name: &str) -> Option<Kc> {
+        let name = name.strip_prefix("KEY_")?;
+        Some(match name {
+            "MINUS" => Kc::Minus,
+            "EQUAL" => Kc::Equals,
+            "LEFTBRACE" => Kc::LeftBracket,
+            "RIGHTBRACE" => Kc::RightBracket,
+            "BACKSLASH" => Kc::Backslash,
+            "GRAVE" => Kc::Grave,
+            "APOSTROPHE" => Kc::Quote,
+            "SEMICOLON" => Kc::Semicolon,
+            "COMMA" => Kc::Comma,
+            "DOT" => Kc::Dot,
+            "SLASH" => Kc::Slash,
+            "SPACE" => Kc::Space,
+            "ENTER" => Kc::Enter,
+            "BACKSPACE" => Kc::Backspace,
+            "LEFTSHIFT" | "RIGHTSHIFT" => Kc::Shift,
+            "LEFTCTRL" | "RIGHTCTRL" => Kc::Ctrl,
+            "LEFTALT" | "RIGHTALT" => Kc::Alt,
+            "LEFTMETA" | "RIGHTMETA" => Kc::Gui,
+            "ESC" => Kc::Escape,
+            "TAB" => Kc::Tab,
+            "DELETE" => Kc::Delete,
+            "LEFT" => Kc::Left,
+            "DOWN" => Kc::Down,
+            "UP" => Kc::Up,
+            "RIGHT" => Kc::Right,
+            "HOME" => Kc::Home,
+            "END" => Kc::End,
+            "PAGEUP" => Kc::PageUp,
+            "PAGEDOWN" => Kc::PageDown,
+            // Letters, numbers and F keys.
+            name => Kc::from_str(&name.to_lowercase()).or_else(|_| Kc::from_str(name)).ok()?,
+        })
+    }
+
+    // QMK keycode for this key, e.g. KC_A, KC_LPRN or MO(1).
+    #[must_use]
+    pub fn qmk(self) -> String {
+        let name = match self {
+            Kc::None => "NO",
+            Kc::Num0 => "0",
+            Kc::Num1 => "1",
+            Kc::Num2 => "2",
+            Kc::Num3 => "3",
+            Kc::Num4 => "4",
+            Kc::Num5 => "5",
+            Kc::Num6 => "6",
+            Kc::Num7 => "7",
+            Kc::Num8 => "8",
+            Kc::Num9 => "9",
+            Kc::RightParen => "RPRN",
+            Kc::Exclamation => "EXLM",
+            Kc::AtSign => "AT",
+            Kc::Hash => "HASH",
+            Kc::DollarSign => "DLR",
+            Kc::PercentSign => "PERC",
+            Kc::Caret => "CIRC",
+            Kc::Ampersand => "AMPR",
+            Kc::Asterisk => "ASTR",
+            Kc::LeftParen => "LPRN",
+            Kc::Minus => "MINS",
+            Kc::Equals => "EQL",
+            Kc::LeftBracket => "LBRC",
+            Kc::RightBracket => "RBRC",
+            Kc::Backslash => "BSLS",
+            Kc::Grave => "GRV",
+            Kc::Quote => "QUOT",
+            Kc::Semicolon => "SCLN",
+            Kc::Comma => "COMM",
+            Kc::Dot => "DOT",
+            Kc::Slash => "SLSH",
+            Kc::Underscore => "UNDS",
+            Kc::Plus => "PLUS",
+            Kc::LeftBrace => "LCBR",
+            Kc::RightBrace => "RCBR",
+            Kc::Bar => "PIPE",
+            Kc::Tilde => "TILD",
+            Kc::DoubleQuote => "DQUO",
+            Kc::Colon => "COLN",
+            Kc::LeftAngle => "LABK",
+            Kc::RightAngle => "RABK",
+            Kc::QuestionMark => "QUES",
+            Kc::A => "A",
+            Kc::B => "B",
+            Kc::C => "C",
+            Kc::D => "D",
+            Kc::E => "E",
+            Kc::F => "F",
+            Kc::G => "G",
+            Kc::H => "H",
+            Kc::I => "I",
+            Kc::J => "J",
+            Kc::K => "K",
+            Kc::L => "L",
+            Kc::M => "M",
+            Kc::N => "N",
+            Kc::O => "O",
+            Kc::P => "P",
+            Kc::Q => "Q",
+            Kc::R => "R",
+            Kc::S => "S",
+            Kc::T => "T",
+            Kc::U => "U",
+            Kc::V => "V",
+            Kc::W => "W",
+            Kc::X => "X",
+            Kc::Y => "Y",
+            Kc::Z => "Z",
+            Kc::F1 => "F1",
+            Kc::F2 => "F2",
+            Kc::F3 => "F3",
+            Kc::F4 => "F4",
+            Kc::F5 => "F5",
+            Kc::F6 => "F6",
+            Kc::F7 => "F7",
+            Kc::F8 => "F8",
+            Kc::F9 => "F9",
+            Kc::F10 => "F10",
+            Kc::F11 => "F11",
+            Kc::F12 => "F12",
+            Kc::Space => "SPC",
+            Kc::Enter => "ENT",
+            Kc::Backspace => "BSPC",
+            Kc::Shift => "LSFT",
+            Kc::Ctrl => "LCTL",
+            Kc::Alt => "LALT",
+            Kc::Gui => "LGUI",
+            Kc::Escape => "ESC",
+            Kc::Tab => "TAB",
+            Kc::Delete => "DEL",
+            Kc::Left => "LEFT",
+            Kc::Down => "DOWN",
+            Kc::Up => "UP",
+            Kc::Right => "RGHT",
+            Kc::Home => "HOME",
+            Kc::End => "END",
+            Kc::PageUp => "PGUP",
+            Kc::PageDown => "PGDN",
+            Kc::Mo1 | Kc::Mo2 | Kc::Mo3 | Kc::Mo4 | Kc::Mo5 | Kc::Mo6 | Kc::Mo7 => {
+                return format!("MO({})", self.mo_layer().unwrap_or_default());
+            }
+        };
+        format!("KC_{name}")
+    }
+
+    // Key pressed with shift to type this symbol on a standard US host.
+    #[must_use]
+    pub fn unshifted(self) -> Option<Kc> {
+        Some(match self {
+            Kc::RightParen => Kc::Num0,
+            Kc::Exclamation => Kc::Num1,
+            Kc::AtSign => Kc::Num2,
+            Kc::Hash => Kc::Num3,
+            Kc::DollarSign => Kc::Num4,
+            Kc::PercentSign => Kc::Num5,
+            Kc::Caret => Kc::Num6,
+            Kc::Ampersand => Kc::Num7,
+            Kc::Asterisk => Kc::Num8,
+            Kc::LeftParen => Kc::Num9,
+            Kc::Underscore => Kc::Minus,
+            Kc::Plus => Kc::Equals,
+            Kc::LeftBrace => Kc::LeftBracket,
+            Kc::RightBrace => Kc::RightBracket,
+            Kc::Bar => Kc::Backslash,
+            Kc::Tilde => Kc::Grave,
+            Kc::DoubleQuote => Kc::Quote,
+            Kc::Colon => Kc::Semicolon,
+            Kc::LeftAngle => Kc::Comma,
+            Kc::RightAngle => Kc::Dot,
+            Kc::QuestionMark => Kc::Slash,
+            _ => return None,
+        })
+    }
+}
+
+// Keycodes are stored by name, as in the legacy config format.
+impl Serialize for Kc {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Kc {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(d)?;
+        Kc::from_str(&name).map_err(|_| de::Error::custom(format!("unknown key {name}")))
+    }
 }
 
 pub const QWERTY: [Kc; 30] = [